@@ -0,0 +1,137 @@
+//! The `diff-faces` subcommand: runs face detection with two detector configurations over the
+//! same folder and reports how the detections differ for each image. Nothing is resized.
+
+use crate::{detect_faces_with, iou, DetectorParams, MODEL_DATA};
+use clap::Args;
+use eyre::{Result, WrapErr};
+use rayon::prelude::*;
+use rustface::FaceInfo;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+#[derive(Args)]
+pub struct DiffFacesArgs {
+    /// The folder of images to run detection on.
+    img_path: PathBuf,
+    /// Detector settings for the baseline run (e.g. min-face-size=20,score-thresh=2.0)
+    #[clap(long, default_value = "")]
+    baseline: DetectorParams,
+    /// Model file for the baseline run. Defaults to the embedded model.
+    #[clap(long)]
+    baseline_model: Option<PathBuf>,
+    /// Detector settings for the candidate run, in the same format as --baseline
+    #[clap(long, default_value = "")]
+    candidate: DetectorParams,
+    /// Model file for the candidate run. Defaults to the embedded model.
+    #[clap(long)]
+    candidate_model: Option<PathBuf>,
+    /// Print the comparison as JSON instead of a table
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct ImageDiff {
+    path: PathBuf,
+    baseline_faces: usize,
+    candidate_faces: usize,
+    /// Faces found by both runs, paired by highest overlap
+    matched: usize,
+    /// Mean IoU of the matched pairs, absent when nothing matched
+    mean_iou: Option<f64>,
+}
+
+pub fn run(args: &DiffFacesArgs) -> Result<()> {
+    let baseline_model = read_model_file(args.baseline_model.as_deref())?;
+    let candidate_model = read_model_file(args.candidate_model.as_deref())?;
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&args.img_path)
+        .wrap_err_with(|| format!("Failed to read directory: {}", args.img_path.display()))?
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                error!("Failed to read directory entry: {}", e);
+                None
+            }
+        })
+        .collect();
+    paths.sort();
+
+    let diffs: Vec<ImageDiff> = paths.par_iter()
+        .filter_map(|path| {
+            let img = match image::open(path) {
+                Ok(img) => img,
+                Err(_) => {
+                    warn!("Skipping unsupported or broken file: {}", path.display());
+                    return None;
+                }
+            };
+            let detections = detect_faces_with(&img, &baseline_model, &args.baseline)
+                .and_then(|baseline| Ok((baseline, detect_faces_with(&img, &candidate_model, &args.candidate)?)));
+            match detections {
+                Ok((baseline, candidate)) => Some(compare(path, &baseline, &candidate)),
+                Err(e) => {
+                    error!("Failed detecting faces in {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&diffs)?);
+    } else {
+        print_table(&diffs);
+    }
+
+    Ok(())
+}
+
+fn read_model_file(path: Option<&Path>) -> Result<Vec<u8>> {
+    match path {
+        Some(path) => fs::read(path).wrap_err_with(|| format!("Failed to read model: {}", path.display())),
+        None => Ok(MODEL_DATA.to_vec()),
+    }
+}
+
+/// Pairs baseline and candidate faces greedily by descending IoU, each face used at most once.
+fn compare(path: &Path, baseline: &[FaceInfo], candidate: &[FaceInfo]) -> ImageDiff {
+    let mut pairs: Vec<(f64, usize, usize)> = baseline.iter().enumerate()
+        .flat_map(|(b, base)| candidate.iter().enumerate().map(move |(c, cand)| (iou(base.bbox(), cand.bbox()), b, c)))
+        .filter(|(overlap, _, _)| *overlap > 0.0)
+        .collect();
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let mut baseline_used = vec![false; baseline.len()];
+    let mut candidate_used = vec![false; candidate.len()];
+    let mut matched_ious = Vec::new();
+    for (overlap, b, c) in pairs {
+        if !baseline_used[b] && !candidate_used[c] {
+            baseline_used[b] = true;
+            candidate_used[c] = true;
+            matched_ious.push(overlap);
+        }
+    }
+
+    ImageDiff {
+        path: path.to_path_buf(),
+        baseline_faces: baseline.len(),
+        candidate_faces: candidate.len(),
+        matched: matched_ious.len(),
+        mean_iou: (!matched_ious.is_empty()).then(|| matched_ious.iter().sum::<f64>() / matched_ious.len() as f64),
+    }
+}
+
+fn print_table(diffs: &[ImageDiff]) {
+    println!("{:<40} {:>8} {:>9} {:>7} {:>8}", "image", "baseline", "candidate", "matched", "mean iou");
+    for diff in diffs {
+        let name = diff.path.file_name().map_or_else(|| diff.path.display().to_string(), |n| n.to_string_lossy().to_string());
+        let mean_iou = diff.mean_iou.map_or_else(|| "-".to_string(), |v| format!("{:.3}", v));
+        println!("{:<40} {:>8} {:>9} {:>7} {:>8}", name, diff.baseline_faces, diff.candidate_faces, diff.matched, mean_iou);
+    }
+
+    let changed = diffs.iter().filter(|d| d.baseline_faces != d.candidate_faces).count();
+    println!("{} of {} images changed face count", changed, diffs.len());
+}
@@ -2,16 +2,48 @@ extern crate rustface;
 extern crate rayon;
 extern crate indicatif;
 
-use indicatif::{ProgressBar, ProgressStyle};
+mod diff_faces;
+mod probe;
+mod s3;
+mod score_histogram;
+mod webp;
+
+use indicatif::{HumanBytes, ProgressBar, ProgressState, ProgressStyle};
 use rayon::prelude::*;
-use clap::Parser;
-use image::{GenericImageView, imageops, ImageFormat};
-use rustface::ImageData;
+use clap::{Parser, Subcommand, ValueEnum};
+use color_quant::NeuQuant;
+use image::{ColorType, DynamicImage, GenericImageView, ImageBuffer, imageops, ImageFormat, Pixel, RgbImage, Rgba, RgbaImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::ColorMap;
+use rustface::{Detector, FaceInfo, ImageData, Model, Rectangle};
+use serde::Serialize;
+use std::collections::HashSet;
 use std::fs;
 use std::path::{Path, PathBuf};
 use eyre::{eyre, Result, WrapErr};
-use tracing::{error, warn};
-use std::io::Cursor;
+use tracing::{error, info, warn};
+use std::io::{BufWriter, Cursor, Seek, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Output formats by name and MIME type, in the order preferred when an Accept header ranks them equally.
+const OUTPUT_FORMATS: &[(&str, &str)] = &[
+    ("jpg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("tiff", "image/tiff"),
+    ("bmp", "image/bmp"),
+];
+
+/// Total size of the outputs written so far, shown on the batch progress bar.
+static BYTES_WRITTEN: AtomicU64 = AtomicU64::new(0);
+
+/// Final size of every output written so far, collected for --output-dimensions-report.
+static OUTPUT_DIMENSIONS: Mutex<Vec<OutputDimensions>> = Mutex::new(Vec::new());
 
 const MODEL_DATA: &[u8] = include_bytes!("model/seeta_fd_frontal_v1.0.bin");
 const MIN_FACE_SIZE: u32 = 20;
@@ -21,178 +53,2191 @@ const SLIDE_WINDOW_STEP_X: u32 = 4;
 const SLIDE_WINDOW_STEP_Y: u32 = 4;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// The path to the image or folder to be resized.
-    img_path: PathBuf,
+    #[clap(required = true)]
+    img_path: Option<PathBuf>,
     /// Resize dimensions. Format: widthxheight (e.g. 800x600)
     #[clap(short, long, default_value = "2000x2000")]
     size: String,
-    /// Desired output format (png, jpg, gif, bmp, tiff)
+    /// Desired output format (png, jpg, gif, bmp, tiff, or apng to keep GIF and PNG animations)
     #[clap(short = 'f', long = "format", default_value = "jpg")]
     image_format: String,
-    /// The path to save the resized image or folder for multiple images.
+    /// The path to save the resized image or folder for multiple images. May be an
+    /// s3://bucket/prefix/ URL when built with the `s3` feature.
     #[clap(short, long)]
     output_path: Option<PathBuf>,
+    /// Exit with an error once the batch completes if any image failed, reporting the first failure.
+    #[clap(long)]
+    report_first_error: bool,
+    /// Split the resized image into tiles named {stem}_{col}_{row}. Format: widthxheight (e.g. 256x256)
+    #[clap(long)]
+    tile: Option<String>,
+    /// Color used to pad edge tiles, as hex RRGGBB or RRGGBBAA
+    #[clap(long, default_value = "ffffff")]
+    background: String,
+    /// Re-open each output after writing to confirm it decodes at the expected dimensions.
+    #[clap(long)]
+    validate: bool,
+    /// Number of geometric steps used to reach the target size. Values above 1 trade speed for
+    /// smoother results on very large reductions.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    scale_steps: u32,
+    /// Shrink the face crop so the detected face spans at least this percentage (0-100] of it
+    #[clap(long, value_parser = parse_percentage)]
+    face_fill_pct: Option<f32>,
+    /// Shift the crop center horizontally by this fraction of the crop size (-0.5 to 0.5, negative is left)
+    #[clap(long, default_value_t = 0.0, value_parser = parse_bias, allow_negative_numbers = true)]
+    crop_bias_x: f32,
+    /// Shift the crop center vertically by this fraction of the crop size (-0.5 to 0.5, negative is up)
+    #[clap(long, default_value_t = 0.0, value_parser = parse_bias, allow_negative_numbers = true)]
+    crop_bias_y: f32,
+    /// Bits per channel carried through resize and encode (8, or 16 for png and tiff output)
+    #[clap(long, default_value_t = 8, value_parser = parse_bit_depth)]
+    bit_depth: u8,
+    /// Write one crop per cluster of nearby faces, named {stem}_{index}
+    #[clap(long)]
+    face_cluster: bool,
+    /// Faces whose centers are within this many face sizes of each other share a cluster
+    #[clap(long, default_value_t = 2.0, requires = "face_cluster")]
+    cluster_distance: f32,
+    /// Quantize png and gif output to at most this many colors (2-256)
+    #[clap(long, value_parser = clap::value_parser!(u16).range(2..=256))]
+    colors: Option<u16>,
+    /// Use Floyd-Steinberg dithering when quantizing with --colors
+    #[clap(long, requires = "colors")]
+    dither: bool,
+    /// Also detect faces on the image rotated 180 degrees and keep whichever orientation has the
+    /// more confident face, for upside-down scans
+    #[clap(long)]
+    detect_flip_180: bool,
+    /// Name outputs 0001, 0002, ... in sorted input order instead of after the source file, and
+    /// write the original to new name mapping to name_mapping.csv in the output folder
+    #[clap(long)]
+    sequential_names: bool,
+    /// First number used by --sequential-names
+    #[clap(long, default_value_t = 1, requires = "sequential_names")]
+    start_index: u64,
+    /// Zero-pad --sequential-names numbers to this many digits
+    #[clap(long, default_value_t = 4, requires = "sequential_names")]
+    pad: usize,
+    /// Scale the requested --size down, keeping its aspect ratio, so outputs never exceed this many megapixels
+    #[clap(long, value_parser = parse_megapixels)]
+    max_output_megapixels: Option<f64>,
+    /// Fade alpha to transparent over this many pixels at the crop edge (png, tiff and gif output)
+    #[clap(long)]
+    feather: Option<f32>,
+    /// Mask the output to a circle inscribed in the crop for round avatars (png, tiff and gif output)
+    #[clap(long)]
+    circle_crop: bool,
+    /// Pick the output format from an HTTP Accept header (e.g. "image/avif,image/webp,image/*"),
+    /// overriding --format
+    #[clap(long)]
+    accept: Option<String>,
+    /// Number of face detectors shared by the worker threads. Defaults to the number of threads,
+    /// or the number of images in a folder with fewer images than threads.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    detector_pool_size: Option<u32>,
+    /// Store color premultiplied by alpha instead of straight alpha (png and tiff output)
+    #[clap(long)]
+    premultiplied_output: bool,
+    /// Fail the image when the face detector panics instead of falling back to a center crop
+    #[clap(long)]
+    no_panic_fallback: bool,
+    /// Use the dimensions of this reference image as the target size, overriding --size
+    #[clap(long)]
+    match_size: Option<PathBuf>,
+    /// How the crop is placed: around the detected face, or on the brightness center of mass
+    /// without running face detection
+    #[clap(long, value_enum, default_value_t = CropMode::Face)]
+    crop: CropMode,
+    /// Space kept above the face, as a percentage of the face size. Any --pad-* option crops to
+    /// the padded face instead of the image's shorter side.
+    #[clap(long, value_parser = parse_padding)]
+    pad_top: Option<f32>,
+    /// Space kept below the face, as a percentage of the face size
+    #[clap(long, value_parser = parse_padding)]
+    pad_bottom: Option<f32>,
+    /// Space kept left of the face, as a percentage of the face size
+    #[clap(long, value_parser = parse_padding)]
+    pad_left: Option<f32>,
+    /// Space kept right of the face, as a percentage of the face size
+    #[clap(long, value_parser = parse_padding)]
+    pad_right: Option<f32>,
+    /// Format to write instead, with its own extension, when encoding to --format fails
+    #[clap(long)]
+    format_fallback: Option<String>,
+    /// How many levels of subfolders to process below the input folder (0 is the folder itself).
+    /// With --output-path, outputs keep their subfolder under it.
+    #[clap(long, default_value_t = 0)]
+    max_depth: usize,
+    /// Write outputs from subfolders straight into --output-path, prefixing each name with its
+    /// relative folder (sub__a_resized.jpg) and numbering any names that still collide. The
+    /// original to new name mapping goes to name_mapping.csv in the output folder
+    #[clap(long, requires = "output_path")]
+    flatten: bool,
+    /// Process the folder in batches of this many files, finishing each batch before starting the next
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: Option<u32>,
+    /// Center the crop on this focal point instead of detecting faces, as fractions of the image
+    /// width and height. Format: x,y (e.g. 0.3,0.25)
+    #[clap(long, value_parser = parse_focus)]
+    focus: Option<(f32, f32)>,
+    /// Write every output in each of these formats from the same crop and resize, overriding
+    /// --format (e.g. png,jpg)
+    #[clap(long, value_delimiter = ',', num_args = 1..)]
+    dual_format: Option<Vec<String>>,
+    /// Name outputs after a lowercase, hyphen-separated ASCII slug of the source name, and write
+    /// the original to new name mapping to name_mapping.csv in the output folder
+    #[clap(long, conflicts_with = "sequential_names")]
+    slugify: bool,
+    /// Run a slower second detection pass for small faces when the first pass finds fewer than
+    /// --thorough-min-faces faces
+    #[clap(long)]
+    thorough_detect: bool,
+    /// Face count below which --thorough-detect runs its second pass
+    #[clap(long, default_value_t = 1, requires = "thorough_detect")]
+    thorough_min_faces: usize,
+    /// Size in KiB of the write buffer each output is encoded through. Larger buffers mean fewer,
+    /// bigger writes, which helps on network filesystems.
+    #[clap(long, default_value_t = 8, value_parser = clap::value_parser!(u32).range(1..))]
+    write_buffer_kb: u32,
+    /// Move the face crop's center toward the image center: 1.0 centers on the face, 0.0 on the
+    /// image, and values between blend linearly
+    #[clap(long, default_value_t = 1.0, value_parser = parse_fraction)]
+    gravity_blend: f32,
+    /// Only process images whose EXIF data has GPS coordinates, skipping the rest with a warning
+    #[clap(long)]
+    require_gps: bool,
+    /// How a folder's images are spread across threads: rayon's work-stealing pool, one at a time
+    /// on the main thread, or a fixed set of threads (--detector-pool-size, default one per core)
+    /// fed through a bounded queue
+    #[clap(long, value_enum, default_value_t = Parallelism::Rayon)]
+    parallelism: Parallelism,
+    /// Keep each jpg output under this many KiB by searching smaller dimensions (up to --size) and
+    /// lower quality together
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    target_bytes: Option<u64>,
+    /// Keep GIF and PNG animations when writing webp (requires the `webp` feature)
+    #[clap(long)]
+    animated: bool,
+    /// Embed a small EXIF thumbnail of the resized image in jpg outputs, so file browsers show a
+    /// matching preview. No other metadata is carried over unless --keep-metadata asks for it
+    #[clap(long, conflicts_with = "target_bytes")]
+    strip_but_keep_thumbnail: bool,
+    /// When the run finishes, list the outputs that didn't come out at --size (or the tile size)
+    /// and write every output's dimensions to dimensions_report.json in the output folder
+    #[clap(long)]
+    output_dimensions_report: bool,
+    /// Folders with fewer images than this run sequentially with a single face detector, since
+    /// starting threads and a detector per thread costs more than it saves on a few images. 0
+    /// always uses --parallelism
+    #[clap(long, default_value_t = 2)]
+    min_parallel: usize,
+    /// Carry these kinds of source metadata over to jpg outputs (comma-separated: icc,
+    /// orientation). Everything else, GPS included, is still dropped
+    #[clap(long, value_enum, value_delimiter = ',', conflicts_with = "target_bytes")]
+    keep_metadata: Vec<MetadataKind>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Parallelism {
+    Rayon,
+    Sequential,
+    Pool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CropMode {
+    Face,
+    Brightness,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum MetadataKind {
+    Icc,
+    Orientation,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compare face detection between two detector configurations without resizing anything
+    DiffFaces(diff_faces::DiffFacesArgs),
+    /// Print the dimensions and format of each image from its header, without decoding or resizing
+    Probe(probe::ProbeArgs),
+    /// Print a histogram of face scores across a folder to help choose a score threshold
+    ScoreHistogram(score_histogram::ScoreHistogramArgs),
+}
+
+/// Tunable face detector settings, written as `key=value` pairs separated by commas, e.g.
+/// `min-face-size=40,score-thresh=3.0,pyramid-scale=0.7,step=2x2`. Unset keys keep their defaults.
+#[derive(Clone, Copy, Debug)]
+struct DetectorParams {
+    min_face_size: u32,
+    score_thresh: f64,
+    pyramid_scale_factor: f32,
+    slide_window_step: (u32, u32),
+}
+
+impl Default for DetectorParams {
+    fn default() -> Self {
+        DetectorParams {
+            min_face_size: MIN_FACE_SIZE,
+            score_thresh: SCORE_THRESH,
+            pyramid_scale_factor: PYRAMID_SCALE_FACTOR,
+            slide_window_step: (SLIDE_WINDOW_STEP_X, SLIDE_WINDOW_STEP_Y),
+        }
+    }
+}
+
+impl FromStr for DetectorParams {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let mut params = DetectorParams::default();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got {}", pair))?;
+            let invalid = || format!("Invalid value for {}: {}", key, value);
+            match key {
+                "min-face-size" => params.min_face_size = value.parse().map_err(|_| invalid())?,
+                "score-thresh" => params.score_thresh = value.parse().map_err(|_| invalid())?,
+                "pyramid-scale" => params.pyramid_scale_factor = value.parse().map_err(|_| invalid())?,
+                "step" => params.slide_window_step = parse_dimensions(value).map_err(|e| e.to_string())?,
+                _ => return Err(format!("Unknown detector setting: {}", key)),
+            }
+        }
+
+        // rustface panics on values outside these ranges, so reject them while parsing
+        if params.min_face_size < MIN_FACE_SIZE {
+            return Err(format!("min-face-size must be at least {}", MIN_FACE_SIZE));
+        }
+        if params.score_thresh.is_nan() || params.score_thresh <= 0.0 {
+            return Err("score-thresh must be greater than zero".to_string());
+        }
+        if !(0.01..=0.99).contains(&params.pyramid_scale_factor) {
+            return Err("pyramid-scale must be between 0.01 and 0.99".to_string());
+        }
+        Ok(params)
+    }
 }
 
 fn main() {
     if let Err(err) = run() {
         eprintln!("Error: {:?}", err);
+        std::process::exit(1);
     }
 }
 
 fn run() -> Result<()> {
     // Initialize tracing
     tracing_subscriber::fmt()
+    .with_writer(std::io::stderr)
     .without_time()
     .with_max_level(tracing::Level::TRACE)
     .init();
 
-    let args = Cli::parse();
+    let mut args = Cli::parse();
+
+    if let Some(command) = &args.command {
+        return match command {
+            Command::DiffFaces(diff_args) => diff_faces::run(diff_args),
+            Command::Probe(probe_args) => probe::run(probe_args),
+            Command::ScoreHistogram(histogram_args) => score_histogram::run(histogram_args),
+        };
+    }
+
+    let Some(img_path) = args.img_path.as_deref() else {
+        return Err(eyre!("No image folder provided"));
+    };
+
+    if !img_path.exists() {
+        return Err(eyre!("The provided path does not exist: {}", img_path.display()));
+    }
+
+    if let Some(reference) = &args.match_size {
+        let (width, height) = image::image_dimensions(reference)
+            .wrap_err_with(|| format!("Failed to read the size of reference image: {}", reference.display()))?;
+        args.size = format!("{}x{}", width, height);
+    }
+
+    if let Some(accept) = &args.accept {
+        let format = negotiate_format(accept)
+            .ok_or_else(|| eyre!("None of the supported formats are acceptable for: {}", accept))?;
+        info!("Negotiated output format {} from Accept header", format);
+        args.image_format = format.to_string();
+    }
+
+    if let Some(formats) = &args.dual_format {
+        if formats.iter().any(|format| format.eq_ignore_ascii_case("apng")) {
+            return Err(eyre!("--dual-format can't include apng"));
+        }
+        // Checked before the first format stands in for -f, so the error names --dual-format
+        if formats.iter().any(|format| determine_image_format(format).is_ok_and(|f| f == ImageFormat::WebP)) {
+            return Err(eyre!("--dual-format can't include webp: WebP is only written by -f webp --animated"));
+        }
+        if args.tile.is_some() || args.colors.is_some() {
+            return Err(eyre!("--dual-format can't be combined with --tile or --colors"));
+        }
+        // The first format names outputs in the name mapping and is checked like --format
+        args.image_format = formats[0].clone();
+    }
+
+    // The only WebP encoder available writes animations, so still WebP output isn't offered
+    let webp_output = determine_image_format(&args.image_format).is_ok_and(|format| format == ImageFormat::WebP);
+    if webp_output != args.animated {
+        return Err(eyre!("--animated and -f webp go together: only animated WebP output is supported"));
+    }
+    if args.animated {
+        webp::check_available()?;
+        if args.tile.is_some() || args.colors.is_some() || args.dual_format.is_some() || args.target_bytes.is_some() {
+            return Err(eyre!("--animated can't be combined with --tile, --colors, --dual-format or --target-bytes"));
+        }
+    }
+
+    let output_formats = args.dual_format.clone().unwrap_or_else(|| vec![args.image_format.clone()]);
+    for format in &output_formats {
+        let output_format = determine_image_format(format)?;
+        if args.bit_depth == 16 && !matches!(output_format, ImageFormat::Png | ImageFormat::Tiff) {
+            return Err(eyre!("16-bit output is only supported for png and tiff, not {}", format));
+        }
+
+        if (args.feather.is_some() || args.circle_crop)
+            && !matches!(output_format, ImageFormat::Png | ImageFormat::Tiff | ImageFormat::Gif | ImageFormat::WebP) {
+            return Err(eyre!("--feather and --circle-crop need an alpha-capable format (png, tiff, gif, webp), not {}", format));
+        }
+
+        if args.premultiplied_output && !matches!(output_format, ImageFormat::Png | ImageFormat::Tiff) {
+            return Err(eyre!("--premultiplied-output only applies to png and tiff output, not {}", format));
+        }
+
+        if !args.keep_metadata.is_empty() && output_format != ImageFormat::Jpeg {
+            return Err(eyre!("--keep-metadata only applies to jpg output, not {}", format));
+        }
+    }
+
+    let requested = parse_dimensions(&args.size)?;
+    let target = target_dimensions(&args)?;
+    if target != requested {
+        info!(
+            "Requested size {}x{} exceeds the {} megapixel cap, resizing to {}x{} instead",
+            requested.0, requested.1, args.max_output_megapixels.unwrap_or_default(), target.0, target.1
+        );
+    }
+
+    if args.feather.is_some_and(|radius| radius < 0.0) {
+        return Err(eyre!("--feather must not be negative"));
+    }
+
+    if args.colors.is_some() && !matches!(determine_image_format(&args.image_format)?, ImageFormat::Png | ImageFormat::Gif) {
+        return Err(eyre!("--colors only applies to png and gif output, not {}", args.image_format));
+    }
+
+    if args.colors.is_some() && args.bit_depth == 16 {
+        return Err(eyre!("--colors can't be combined with --bit-depth 16"));
+    }
+
+    if args.image_format.eq_ignore_ascii_case("apng") && (args.tile.is_some() || args.colors.is_some() || args.bit_depth == 16) {
+        return Err(eyre!("apng output can't be combined with --tile, --colors or --bit-depth 16"));
+    }
+
+    if args.target_bytes.is_some() {
+        if determine_image_format(&args.image_format)? != ImageFormat::Jpeg {
+            return Err(eyre!("--target-bytes only applies to jpg output, not {}", args.image_format));
+        }
+        if args.tile.is_some() || args.dual_format.is_some() {
+            return Err(eyre!("--target-bytes can't be combined with --tile or --dual-format"));
+        }
+    }
+
+    if let Some(fallback) = &args.format_fallback {
+        if fallback.eq_ignore_ascii_case("apng") {
+            return Err(eyre!("--format-fallback can't be apng"));
+        }
+        if determine_image_format(fallback)? == ImageFormat::WebP {
+            return Err(eyre!("--format-fallback can't be webp: WebP is only written by -f webp --animated"));
+        }
+    }
 
-    if !args.img_path.exists() {
-        return Err(eyre!("The provided path does not exist: {}", args.img_path.display()));
+    if args.output_path.as_deref().is_some_and(s3::is_s3_url) {
+        s3::init()?;
     }
 
-    if args.img_path.is_dir() {
-        process_directory(&args)
+    if img_path.is_dir() {
+        process_directory(img_path, &args)
     } else {
-        Err(eyre!("Provided path is not a directory."))
+        process_single_image(img_path, &args)
     }
 }
 
-fn process_directory(args: &Cli) -> Result<()> {
-    let entries: Vec<_> = fs::read_dir(&args.img_path)
-        .wrap_err_with(|| format!("Failed to read directory: {}", args.img_path.display()))?
-        .collect();
+fn process_single_image(img_path: &Path, args: &Cli) -> Result<()> {
+    if args.require_gps && !has_gps(img_path) {
+        warn!("Skipping image without GPS coordinates: {}", img_path.display());
+        return Ok(());
+    }
+
+    // The total work isn't known up front, so show a spinner with the current stage
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(ProgressStyle::default_spinner().template("{spinner} [{elapsed}] {msg}")?);
+    pb.enable_steady_tick(Duration::from_millis(100));
+
+    let file_stem = if args.sequential_names {
+        format!("{:0width$}", args.start_index, width = args.pad)
+    } else if args.slugify {
+        slugify(&file_stem(img_path)?)
+    } else {
+        file_stem(img_path)?
+    };
+
+    let detectors = DetectorPool::new(1, &DetectorParams::default())?;
+    match process_image(img_path, &file_stem, &detectors, args, Some(&pb)) {
+        Ok(output_paths) => {
+            pb.finish_with_message(format!("Processed {}", img_path.display()));
+            if args.sequential_names || args.slugify {
+                let mapping: Vec<(PathBuf, PathBuf)> = output_paths.into_iter()
+                    .map(|output_path| (img_path.to_path_buf(), output_path))
+                    .collect();
+                write_name_mapping(&output_dir(img_path, args), &mapping)?;
+            }
+            if args.output_dimensions_report {
+                write_dimensions_report(&output_dir(img_path, args))?;
+            }
+            Ok(())
+        }
+        Err(e) => {
+            pb.abandon();
+            Err(e.wrap_err(format!("Failed processing image {}", img_path.display())))
+        }
+    }
+}
+
+fn process_directory(img_path: &Path, args: &Cli) -> Result<()> {
+    let mut entries = Vec::new();
+    collect_entries(img_path, args.max_depth, &mut entries)?;
+    // Filtered before anything else so skipped images don't take a sequential number or a tick
+    if args.require_gps {
+        entries.retain(|entry| match entry {
+            Ok(path) if path.is_file() && ImageFormat::from_path(path).is_ok() && !has_gps(path) => {
+                warn!("Skipping image without GPS coordinates: {}", path.display());
+                false
+            }
+            _ => true,
+        });
+    }
+    // Sorted so numbering and error reporting don't depend on directory listing order
+    entries.sort_by_key(|entry| entry.as_ref().ok().cloned());
+
+    // Numbers and slugs are handed out up front to files with an image extension, so they follow
+    // sorted input order no matter which worker finishes first
+    let assigned_stems: Vec<Option<String>> = if args.sequential_names {
+        let mut next = args.start_index;
+        entries.iter()
+            .map(|entry| {
+                let path = entry.as_ref().ok()?;
+                ImageFormat::from_path(path).ok()?;
+                let stem = format!("{:0width$}", next, width = args.pad);
+                next += 1;
+                Some(stem)
+            })
+            .collect()
+    } else if args.slugify || args.flatten {
+        // Sources that slug or flatten to the same output get -2, -3, ... rather than overwriting
+        // each other
+        let mut taken = HashSet::new();
+        entries.iter()
+            .map(|entry| {
+                let path = entry.as_ref().ok()?;
+                ImageFormat::from_path(path).ok()?;
+                let stem = file_stem(path).ok()?;
+                let mut stem = if args.slugify { slugify(&stem) } else { stem };
+                if args.output_path.is_some() {
+                    stem = nested_stem(img_path, path, stem, args.flatten);
+                }
+                let mut unique = stem.clone();
+                let mut copy = 1;
+                while !taken.insert(determine_output_path(path, &unique, args)) {
+                    copy += 1;
+                    unique = format!("{}-{}", stem, copy);
+                }
+                Some(unique)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let name_mapping: Mutex<Vec<(usize, PathBuf, PathBuf)>> = Mutex::new(Vec::new());
+
+    let image_count = entries.iter()
+        .filter(|entry| entry.as_ref().is_ok_and(|path| ImageFormat::from_path(path).is_ok()))
+        .count();
+    let small_batch = image_count < args.min_parallel && args.parallelism != Parallelism::Sequential;
+    let parallelism = if small_batch {
+        info!("{} images is below --min-parallel {}, processing them sequentially", image_count, args.min_parallel);
+        Parallelism::Sequential
+    } else {
+        args.parallelism
+    };
+
+    let pool_size = match (args.detector_pool_size, parallelism) {
+        _ if small_batch => 1,
+        (Some(size), _) => size as usize,
+        (None, Parallelism::Sequential) => 1,
+        // Detectors past one per image would never be checked out, so they aren't built
+        (None, _) => rayon::current_num_threads().min(image_count.max(1)),
+    };
+    let detectors = DetectorPool::new(pool_size, &DetectorParams::default())?;
 
     // Create a new progress bar instance
     let pb = ProgressBar::new(entries.len() as u64);
     pb.set_style(ProgressStyle::default_bar()
-        .template("[{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}")?
+        .template("[{bar:40.cyan/blue}] {pos}/{len} ({eta}) {written} {msg}")?
+        .with_key("written", |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+            let bytes = BYTES_WRITTEN.load(Ordering::Relaxed);
+            let per_sec = bytes as f64 / state.elapsed().as_secs_f64().max(0.001);
+            let _ = write!(w, "{} ({}/s)", HumanBytes(bytes), HumanBytes(per_sec as u64));
+        })
         .progress_chars("#>-"));
 
-    entries.par_iter()
-        .filter_map(|entry_result| {
-            match entry_result {
-                Ok(entry) => Some(entry),
+    // First failure by position in the directory listing, not by completion time
+    let first_error: Mutex<Option<(usize, eyre::Report)>> = Mutex::new(None);
+    let record_error = |index: usize, err: eyre::Report| {
+        let mut first = first_error.lock().unwrap_or_else(|e| e.into_inner());
+        if first.as_ref().is_none_or(|(first_index, _)| index < *first_index) {
+            *first = Some((index, err));
+        }
+    };
+
+    let process_entry = |index: usize, entry_result: &std::io::Result<PathBuf>| {
+        let entry_path = match entry_result {
+            Ok(path) => path.clone(),
+            Err(e) => {
+                error!("Failed to read directory entry: {}", e);
+                record_error(index, eyre!("Failed to read directory entry: {}", e));
+                pb.inc(1);
+                return;
+            }
+        };
+        if image::open(&entry_path).is_ok() {
+            let file_stem = match assigned_stems.get(index) {
+                Some(Some(stem)) => Ok(stem.clone()),
+                Some(None) => Err(eyre!("No output name was assigned")),
+                None if args.output_path.is_some() => file_stem(&entry_path).map(|stem| nested_stem(img_path, &entry_path, stem, args.flatten)),
+                None => file_stem(&entry_path),
+            };
+            let result = file_stem.and_then(|stem| process_image(&entry_path, &stem, &detectors, args, None));
+            match result {
+                Ok(output_paths) if args.sequential_names || args.slugify || args.flatten => {
+                    let mut name_mapping = name_mapping.lock().unwrap_or_else(|e| e.into_inner());
+                    for output_path in output_paths {
+                        name_mapping.push((index, entry_path.clone(), output_path));
+                    }
+                }
+                Ok(_) => {}
                 Err(e) => {
-                    error!("Failed to read directory entry: {}", e);
-                    None
+                    error!("Failed processing image {}: {}", entry_path.display(), e);
+                    record_error(index, e.wrap_err(format!("Failed processing image {}", entry_path.display())));
                 }
             }
-        })
-        .for_each(|entry| {
-            let entry_path = entry.path();
-            if image::open(&entry_path).is_ok() {
-                if let Err(e) = process_image(&entry_path, &args.size, &args.image_format, args.output_path.as_ref()) {
-                    error!("Failed processing image {}: {}", entry_path.display(), e);
+        } else {
+            warn!("Skipping unsupported or broken file: {}", entry_path.display());
+        }
+        pb.inc(1);  // Increment progress bar after processing each image
+    };
+
+    // Each batch finishes before the next one starts, so only --batch-size entries are in flight
+    let batch_size = args.batch_size.map_or(entries.len(), |size| size as usize).max(1);
+    for (batch, chunk) in entries.chunks(batch_size).enumerate() {
+        let start = batch * batch_size;
+        match parallelism {
+            Parallelism::Rayon => chunk.par_iter()
+                .enumerate()
+                .for_each(|(offset, entry)| process_entry(start + offset, entry)),
+            Parallelism::Sequential => chunk.iter()
+                .enumerate()
+                .for_each(|(offset, entry)| process_entry(start + offset, entry)),
+            Parallelism::Pool => run_in_pool(chunk, pool_size, |offset, entry| process_entry(start + offset, entry)),
+        }
+    }
+
+    if args.sequential_names || args.slugify || args.flatten {
+        let mut name_mapping = name_mapping.into_inner().unwrap_or_else(|e| e.into_inner());
+        name_mapping.sort_by_key(|(index, _, _)| *index);
+        let mapping: Vec<(PathBuf, PathBuf)> = name_mapping.into_iter().map(|(_, from, to)| (from, to)).collect();
+        if let Err(e) = write_name_mapping(&output_dir(img_path, args), &mapping) {
+            error!("Failed to write name mapping: {}", e);
+            record_error(usize::MAX, e);
+        }
+    }
+
+    if args.output_dimensions_report {
+        if let Err(e) = write_dimensions_report(&output_dir(img_path, args)) {
+            error!("Failed to write dimensions report: {}", e);
+            record_error(usize::MAX, e);
+        }
+    }
+
+    let first_error = first_error.into_inner().unwrap_or_else(|e| e.into_inner());
+    match first_error {
+        Some((_, err)) if args.report_first_error => {
+            pb.finish_with_message("Finished with errors.");
+            Err(err)
+        }
+        _ => {
+            pb.finish_with_message("All images processed!"); // Finish the progress bar with a message
+            Ok(())
+        }
+    }
+}
+
+/// Runs `work` on every item from `workers` scoped threads fed through a bounded channel, so only
+/// a couple of items per worker are queued ahead of the ones being processed.
+fn run_in_pool<T: Sync>(items: &[T], workers: usize, work: impl Fn(usize, &T) + Sync) {
+    let (sender, receiver) = mpsc::sync_channel::<(usize, &T)>(workers * 2);
+    let receiver = Mutex::new(receiver);
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            let (receiver, work) = (&receiver, &work);
+            scope.spawn(move || loop {
+                let next = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                match next {
+                    Ok((index, item)) => work(index, item),
+                    Err(_) => break,
                 }
-            } else {
-                warn!("Skipping unsupported or broken file: {}", entry_path.display());
+            });
+        }
+        for item in items.iter().enumerate() {
+            if sender.send(item).is_err() {
+                break;
             }
-            pb.inc(1);  // Increment progress bar after processing each image
-        });
+        }
+        drop(sender);
+    });
+}
 
-    pb.finish_with_message("All images processed!"); // Finish the progress bar with a message
+/// Lists the paths in `dir`, descending `depth` more levels into subfolders. Folders past the
+/// last level are listed like files, and unreadable entries are kept as errors for the caller.
+fn collect_entries(dir: &Path, depth: usize, entries: &mut Vec<std::io::Result<PathBuf>>) -> Result<()> {
+    let listing = fs::read_dir(dir)
+        .wrap_err_with(|| format!("Failed to read directory: {}", dir.display()))?;
+    for entry in listing {
+        match entry.map(|e| e.path()) {
+            Ok(path) if depth > 0 && path.is_dir() => {
+                if let Err(e) = collect_entries(&path, depth - 1, entries) {
+                    entries.push(Err(std::io::Error::other(format!("{:#}", e))));
+                }
+            }
+            entry => entries.push(entry),
+        }
+    }
     Ok(())
 }
 
-fn process_image(img_path: &Path, size: &str, image_format: &str, output_dir: Option<&PathBuf>) -> Result<()> {
-    let dimensions: Vec<&str> = size.split('x').collect();
-    if dimensions.len() != 2 {
-        return Err(eyre!("Invalid size format. Expected format: widthxheight"));
+/// `stem` prefixed with the folder of `path` relative to `root`, so outputs from nested folders
+/// land in the same subfolder under --output-path instead of colliding. With `flatten` the
+/// folders are joined into the name with `__` instead.
+fn nested_stem(root: &Path, path: &Path, stem: String, flatten: bool) -> String {
+    let relative = match path.parent().and_then(|parent| parent.strip_prefix(root).ok()) {
+        Some(relative) if !relative.as_os_str().is_empty() => relative,
+        _ => return stem,
+    };
+    if !flatten {
+        return relative.join(stem).to_string_lossy().to_string();
     }
-    let width: u32 = dimensions[0].parse()?;
-    let height: u32 = dimensions[1].parse()?;
+    let mut parts: Vec<String> = relative.components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect();
+    parts.push(stem);
+    parts.join("__")
+}
 
-    let img = image::open(img_path)
-        .wrap_err_with(|| format!("Failed to open image: {}", img_path.display()))?;
+/// Writes `original,new` rows for every renamed output to name_mapping.csv in `output_dir`.
+fn write_name_mapping(output_dir: &Path, mapping: &[(PathBuf, PathBuf)]) -> Result<()> {
+    let mut csv = String::from("original,new\n");
+    for (original, renamed) in mapping {
+        let renamed = renamed.file_name().map_or_else(|| renamed.display().to_string(), |n| n.to_string_lossy().to_string());
+        csv += &format!("{},{}\n", csv_field(&original.display().to_string()), csv_field(&renamed));
+    }
 
-    let square_crop = face_gravity_crop(&img)?;
-    let resized = imageops::resize(&square_crop, width, height, imageops::FilterType::Lanczos3);
+    let csv_path = output_dir.join("name_mapping.csv");
+    if s3::is_s3_url(&csv_path) {
+        return s3::upload(&csv_path, csv.into_bytes(), "text/csv");
+    }
+    fs::create_dir_all(output_dir).wrap_err_with(|| format!("Failed to create directory: {}", output_dir.display()))?;
+    fs::write(&csv_path, csv).wrap_err_with(|| format!("Failed to write {}", csv_path.display()))
+}
 
-    let output_format = determine_image_format(image_format)?;
-    let output_path = determine_output_path(img_path, image_format, output_dir)?;
+#[derive(Serialize)]
+struct OutputDimensions {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    expected_width: u32,
+    expected_height: u32,
+}
 
-    // Create the directory if it doesn't exist
-    if let Some(parent_dir) = output_path.parent() {
-        if !parent_dir.exists() {
-            fs::create_dir_all(parent_dir).wrap_err_with(|| format!("Failed to create directory: {}", parent_dir.display()))?;
-        }
+impl OutputDimensions {
+    fn deviates(&self) -> bool {
+        (self.width, self.height) != (self.expected_width, self.expected_height)
     }
+}
 
-    resized.save_with_format(output_path.clone(), output_format)
-        .wrap_err_with(|| format!("Failed to save resized image: {}", output_path.display()))?;
-
-    Ok(())
+#[derive(Serialize)]
+struct DimensionsReport<'a> {
+    outputs: &'a [OutputDimensions],
+    deviating: Vec<&'a OutputDimensions>,
 }
 
-fn determine_image_format(image_format: &str) -> Result<ImageFormat> {
-    match image_format.to_lowercase().as_str() {
-        "png" => Ok(ImageFormat::Png),
-        "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
-        "gif" => Ok(ImageFormat::Gif),
-        "bmp" => Ok(ImageFormat::Bmp),
-        "tiff" => Ok(ImageFormat::Tiff),
-        _ => Err(eyre!("Unsupported format: {}", image_format))
+/// Notes the dimensions an output was written at when --output-dimensions-report is set.
+/// `expected` is the tile size for tiles and the --size for everything else.
+fn record_dimensions(output_path: &Path, dimensions: (u32, u32), expected: (u32, u32), args: &Cli) {
+    if !args.output_dimensions_report {
+        return;
     }
+    OUTPUT_DIMENSIONS.lock().unwrap_or_else(|e| e.into_inner()).push(OutputDimensions {
+        path: output_path.to_path_buf(),
+        width: dimensions.0,
+        height: dimensions.1,
+        expected_width: expected.0,
+        expected_height: expected.1,
+    });
 }
 
-fn face_gravity_crop(img: &image::DynamicImage) -> Result<image::DynamicImage> {
-    let (width, height) = img.dimensions();
-    let gray_img = img.to_luma8();
-    let bytes = gray_img.into_raw();
-    let image = ImageData::new(&bytes, width, height);
+/// Warns about each output that came out at a different size than expected and writes every
+/// recorded output to dimensions_report.json in `output_dir`.
+fn write_dimensions_report(output_dir: &Path) -> Result<()> {
+    let mut outputs = std::mem::take(&mut *OUTPUT_DIMENSIONS.lock().unwrap_or_else(|e| e.into_inner()));
+    outputs.sort_by(|a, b| a.path.cmp(&b.path));
+    let deviating: Vec<&OutputDimensions> = outputs.iter().filter(|output| output.deviates()).collect();
+    for output in &deviating {
+        warn!(
+            "{} is {}x{}, expected {}x{}",
+            output.path.display(), output.width, output.height, output.expected_width, output.expected_height
+        );
+    }
+    info!("{} of {} outputs deviate from the expected dimensions", deviating.len(), outputs.len());
 
-    let model_instance = rustface::read_model(Cursor::new(MODEL_DATA))
-        .wrap_err("Failed to read the model from bytes")?;
+    let json = serde_json::to_string_pretty(&DimensionsReport { outputs: &outputs, deviating })?;
+    let report_path = output_dir.join("dimensions_report.json");
+    if s3::is_s3_url(&report_path) {
+        return s3::upload(&report_path, json.into_bytes(), "application/json");
+    }
+    fs::create_dir_all(output_dir).wrap_err_with(|| format!("Failed to create directory: {}", output_dir.display()))?;
+    fs::write(&report_path, json).wrap_err_with(|| format!("Failed to write {}", report_path.display()))
+}
 
-    let mut detector = rustface::create_detector_with_model(model_instance);
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
 
-    detector.set_min_face_size(MIN_FACE_SIZE);
-    detector.set_score_thresh(SCORE_THRESH);
-    detector.set_pyramid_scale_factor(PYRAMID_SCALE_FACTOR);
-    detector.set_slide_window_step(SLIDE_WINDOW_STEP_X, SLIDE_WINDOW_STEP_Y);
+/// `file_stem` names the outputs; `progress`, when given, is updated with the stage currently running.
+/// Returns the path written for each crop, which differs from the planned one after a format fallback.
+fn process_image(img_path: &Path, file_stem: &str, detectors: &DetectorPool, args: &Cli, progress: Option<&ProgressBar>) -> Result<Vec<PathBuf>> {
+    let (width, height) = target_dimensions(args)?;
 
-    if let Some(face) = detector.detect(&image).into_iter().next() {
-        let dimension = width.min(height);
-        let face_center_x = face.bbox().x() + (face.bbox().width() / 2) as i32;
-        let face_center_y = face.bbox().y() + (face.bbox().height() / 2) as i32;
+    set_stage(progress, "Decoding");
+    let animated = args.animated || args.image_format.eq_ignore_ascii_case("apng");
+    let animation = if animated { Some(decode_animation(img_path)?) } else { None };
+    let img = match &animation {
+        Some(animation) => DynamicImage::ImageRgba8(animation.frames[0].buffer().clone()),
+        None => image::open(img_path).wrap_err_with(|| format!("Failed to open image: {}", img_path.display()))?,
+    };
+    let mut metadata = SourceMetadata::read(img_path, args);
 
-        let x = (face_center_x as u32).saturating_sub(dimension / 2);
-        let y = (face_center_y as u32).saturating_sub(dimension / 2);
+    let fixed_window = match (args.focus, args.crop) {
+        (Some(focus), _) => Some(focus_crop(&img, focus, (width, height), args)),
+        (None, CropMode::Brightness) => Some(brightness_crop(&img, (width, height), args)),
+        (None, CropMode::Face) => None,
+    };
+    if let Some(window) = fixed_window {
+        let output_path = match &animation {
+            Some(animation) => write_animation(animation, window, false, img_path, file_stem, (width, height), args, progress)?,
+            None => write_output(&window.apply(&img), img_path, file_stem, (width, height), &metadata, args, progress)?,
+        };
+        return Ok(vec![output_path]);
+    }
 
-        Ok(img.crop_imm(x, y, dimension, dimension))
+    set_stage(progress, "Detecting faces");
+    let (img, faces, flipped) = detect_oriented(img, img_path, detectors, args)?;
+    if flipped {
+        metadata.rotate180();
+    }
+    let crops: Vec<(String, CropWindow)> = if args.face_cluster && !faces.is_empty() {
+        cluster_faces(&faces, args.cluster_distance).iter()
+            .enumerate()
+            .map(|(index, cluster)| (format!("{}_{}", file_stem, index), cluster_crop(&img, cluster, args)))
+            .collect()
+    } else if args.face_cluster {
+        vec![(file_stem.to_string(), center_crop(&img, args))]
     } else {
-        Ok(center_crop(img))
+        vec![(file_stem.to_string(), face_gravity_crop(&img, &faces, args))]
+    };
+
+    let mut output_paths = Vec::with_capacity(crops.len());
+    for (stem, window) in crops {
+        output_paths.push(match &animation {
+            Some(animation) => write_animation(animation, window, flipped, img_path, &stem, (width, height), args, progress)?,
+            None => write_output(&window.apply(&img), img_path, &stem, (width, height), &metadata, args, progress)?,
+        });
     }
+    Ok(output_paths)
 }
 
-fn center_crop(img: &image::DynamicImage) -> image::DynamicImage {
-    let (width, height) = img.dimensions();
-    let dimension = width.min(height);
-    let x = (width / 2) - (dimension / 2);
-    let y = (height / 2) - (dimension / 2);
-    img.crop_imm(x, y, dimension, dimension)
+/// Decoded frames of an animated GIF or PNG, with the number of times it plays (0 is forever).
+/// Still images decode to a single frame that plays once.
+struct Animation {
+    frames: Vec<image::Frame>,
+    plays: u32,
+}
+
+fn decode_animation(img_path: &Path) -> Result<Animation> {
+    use image::AnimationDecoder;
+
+    let bytes = fs::read(img_path).wrap_err_with(|| format!("Failed to open image: {}", img_path.display()))?;
+    let decode_err = || format!("Failed to decode animation: {}", img_path.display());
+    let format = image::guess_format(&bytes).wrap_err_with(decode_err)?;
+
+    let (frames, plays) = match format {
+        ImageFormat::Gif => {
+            let decoder = image::codecs::gif::GifDecoder::new(Cursor::new(&bytes)).wrap_err_with(decode_err)?;
+            (decoder.into_frames().collect_frames().wrap_err_with(decode_err)?, gif_loop_count(&bytes))
+        }
+        ImageFormat::Png => {
+            let plays = png::Decoder::new(Cursor::new(&bytes)).read_info().ok()
+                .and_then(|reader| reader.info().animation_control().map(|control| control.num_plays))
+                .unwrap_or(1);
+            let decoder = image::codecs::png::PngDecoder::new(Cursor::new(&bytes)).wrap_err_with(decode_err)?;
+            if decoder.is_apng() {
+                (decoder.apng().into_frames().collect_frames().wrap_err_with(decode_err)?, plays)
+            } else {
+                let still = DynamicImage::from_decoder(decoder).wrap_err_with(decode_err)?;
+                (vec![image::Frame::new(still.to_rgba8())], 1)
+            }
+        }
+        _ => {
+            let still = image::load_from_memory_with_format(&bytes, format).wrap_err_with(decode_err)?;
+            (vec![image::Frame::new(still.to_rgba8())], 1)
+        }
+    };
+
+    if frames.is_empty() {
+        return Err(eyre!("Animation has no frames: {}", img_path.display()));
+    }
+    Ok(Animation { frames, plays })
 }
 
-fn determine_output_path(original_path: &Path, format: &str, output_dir: Option<&PathBuf>) -> Result<PathBuf> {
-    let file_stem = original_path.file_stem()
-        .ok_or_else(|| eyre!("Failed to get the file stem for: {}", original_path.display()))?;
+/// Reads the loop count from a GIF's NETSCAPE2.0 application extension. GIFs without one play
+/// once; a stored count of 0 means forever, and otherwise counts repeats after the first play.
+fn gif_loop_count(bytes: &[u8]) -> u32 {
+    const NETSCAPE: &[u8] = b"NETSCAPE2.0";
+    let Some(start) = bytes.windows(NETSCAPE.len()).position(|window| window == NETSCAPE) else {
+        return 1;
+    };
+    match bytes.get(start + NETSCAPE.len()..start + NETSCAPE.len() + 4) {
+        Some([3, 1, low, high]) => match u16::from_le_bytes([*low, *high]) {
+            0 => 0,
+            repeats => repeats as u32 + 1,
+        },
+        _ => 1,
+    }
+}
+
+/// Crops every frame with the same window, resizes it like a still output and encodes the result
+/// as an animated PNG, or an animated WebP with --animated, keeping each frame's delay and the
+/// loop count.
+#[allow(clippy::too_many_arguments)]
+fn write_animation(animation: &Animation, window: CropWindow, flipped: bool, img_path: &Path, file_stem: &str, size: (u32, u32), args: &Cli, progress: Option<&ProgressBar>) -> Result<PathBuf> {
+    let (width, height) = size;
+    let output_path = determine_output_path(img_path, file_stem, args);
+    let encode_err = || format!("Failed to encode resized image: {}", output_path.display());
+
+    let mut frames = Vec::with_capacity(animation.frames.len());
+    for (index, frame) in animation.frames.iter().enumerate() {
+        if let Some(pb) = progress {
+            pb.set_message(format!("Resizing frame {}/{}", index + 1, animation.frames.len()));
+        }
+        let mut img = DynamicImage::ImageRgba8(frame.buffer().clone());
+        if flipped {
+            img = img.rotate180();
+        }
+        let mut resized = resize_in_steps(&window.apply(&img), width, height, args.scale_steps, 8);
+        if args.feather.is_some() || args.circle_crop {
+            apply_alpha_mask(&mut resized, args.feather.unwrap_or(0.0), args.circle_crop);
+        }
+        if args.premultiplied_output {
+            premultiply_alpha(&mut resized);
+        }
+
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_ms = (numer as f64 / denom.max(1) as f64).round() as u32;
+        frames.push((resized.to_rgba8(), playback_delay_ms(delay_ms)));
+    }
 
-    let mut new_filename = file_stem.to_string_lossy().to_string();
-    new_filename += "_resized.";
-    // new_filename += format;
+    set_stage(progress, "Encoding");
+    if args.animated {
+        let bytes = webp::encode_animation(&frames, animation.plays, size).wrap_err_with(encode_err)?;
+        set_stage(progress, "Writing");
+        save_encoded(bytes, &output_path, ImageFormat::WebP, size, args.validate)?;
+        record_dimensions(&output_path, size, parse_dimensions(&args.size)?, args);
+        return Ok(output_path);
+    }
 
-    let extension = if format == "jpeg" { "jpeg" } else { format };
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(frames.len() as u32, animation.plays).wrap_err_with(encode_err)?;
+    let mut writer = encoder.write_header().wrap_err_with(encode_err)?;
+    for (frame, delay_ms) in &frames {
+        writer.set_frame_delay((*delay_ms).min(u16::MAX as u32) as u16, 1000).wrap_err_with(encode_err)?;
+        writer.write_image_data(frame.as_raw()).wrap_err_with(encode_err)?;
+    }
+    writer.finish().wrap_err_with(encode_err)?;
 
-    new_filename += &format!("_resized.{}", extension);
+    set_stage(progress, "Writing");
+    save_encoded(bytes, &output_path, ImageFormat::Png, size, args.validate)?;
+    record_dimensions(&output_path, size, parse_dimensions(&args.size)?, args);
+    Ok(output_path)
+}
 
-    Ok(if let Some(dir) = output_dir {
-        dir.join(new_filename)
+/// Browsers play frames with a delay of 10ms or less at 100ms, and GIFs are often saved with 0ms
+/// counting on that, so those delays are written as 100ms to keep the speed viewers are used to.
+fn playback_delay_ms(delay_ms: u32) -> u32 {
+    if delay_ms <= 10 {
+        100
     } else {
-        original_path.parent().unwrap_or_else(|| Path::new(".")).join(new_filename)
-    })
+        delay_ms
+    }
+}
+
+fn set_stage(progress: Option<&ProgressBar>, stage: &'static str) {
+    if let Some(pb) = progress {
+        pb.set_message(stage);
+    }
+}
+
+/// Returns the path of the output, the first --dual-format one when there are several, or the
+/// planned name when the output was split into tiles.
+fn write_output(crop: &DynamicImage, img_path: &Path, file_stem: &str, size: (u32, u32), metadata: &SourceMetadata, args: &Cli, progress: Option<&ProgressBar>) -> Result<PathBuf> {
+    let (width, height) = size;
+    // Measured against --size rather than `size`, so the megapixel cap shows up in the report
+    let requested = parse_dimensions(&args.size)?;
+    if let Some(target_kb) = args.target_bytes {
+        set_stage(progress, "Searching for the target size");
+        let output_path = determine_output_path(img_path, file_stem, args);
+        let (bytes, dimensions, quality) = fit_to_bytes(crop, size, target_kb * 1024, args)
+            .wrap_err_with(|| format!("Failed to encode resized image: {}", output_path.display()))?;
+        info!(
+            "{}: {}x{} at quality {}, {} bytes",
+            output_path.display(), dimensions.0, dimensions.1, quality, bytes.len()
+        );
+        set_stage(progress, "Writing");
+        save_encoded(bytes, &output_path, ImageFormat::Jpeg, dimensions, args.validate)?;
+        record_dimensions(&output_path, dimensions, requested, args);
+        return Ok(output_path);
+    }
+
+    set_stage(progress, "Resizing");
+    let mut resized = resize_in_steps(crop, width, height, args.scale_steps, args.bit_depth);
+
+    if args.feather.is_some() || args.circle_crop {
+        apply_alpha_mask(&mut resized, args.feather.unwrap_or(0.0), args.circle_crop);
+    }
+
+    if args.premultiplied_output {
+        premultiply_alpha(&mut resized);
+    }
+
+    let output_format = determine_image_format(&args.image_format)?;
+
+    if let Some(colors) = args.colors {
+        set_stage(progress, "Quantizing");
+        let (quantized, palette) = quantize(resized.to_rgba8(), colors, args.dither);
+
+        // A palette PNG is what actually makes the file smaller; tiles keep the regular encoder
+        if output_format == ImageFormat::Png && args.tile.is_none() {
+            set_stage(progress, "Encoding and writing");
+            let output_path = determine_output_path(img_path, file_stem, args);
+            let dimensions = quantized.dimensions();
+            let written_path = match (encode_indexed_png(&quantized, &palette), &args.format_fallback) {
+                (Ok(bytes), _) => {
+                    save_encoded(bytes, &output_path, output_format, dimensions, args.validate)?;
+                    output_path
+                }
+                (Err(e), Some(fallback)) => save_fallback(&DynamicImage::ImageRgba8(quantized), &output_path, fallback, e, metadata, args)?,
+                (Err(e), None) => return Err(e.wrap_err(format!("Failed to encode resized image: {}", output_path.display()))),
+            };
+            record_dimensions(&written_path, dimensions, requested, args);
+            return Ok(written_path);
+        }
+        resized = DynamicImage::ImageRgba8(quantized);
+    }
+
+    if let Some(tile) = &args.tile {
+        set_stage(progress, "Encoding and writing tiles");
+        let tile_size = parse_dimensions(tile)?;
+        let background = parse_hex_color(&args.background)?;
+        save_tiles(&resized, img_path, file_stem, tile_size, background, metadata, args)?;
+        return Ok(determine_output_path(img_path, file_stem, args));
+    }
+
+    set_stage(progress, "Encoding and writing");
+    let output_path = determine_output_path(img_path, file_stem, args);
+    let Some(formats) = &args.dual_format else {
+        let written_path = save_output(&resized, &output_path, output_format, metadata, args)?;
+        record_dimensions(&written_path, resized.dimensions(), requested, args);
+        return Ok(written_path);
+    };
+    let mut first_path = None;
+    for format in formats {
+        let written_path = save_output(&resized, &output_path.with_extension(format), determine_image_format(format)?, metadata, args)?;
+        record_dimensions(&written_path, resized.dimensions(), requested, args);
+        first_path.get_or_insert(written_path);
+    }
+    Ok(first_path.unwrap_or(output_path))
+}
+
+/// Saves through --format-fallback when it is set, returning the path actually written.
+fn save_output(img: &DynamicImage, output_path: &Path, output_format: ImageFormat, metadata: &SourceMetadata, args: &Cli) -> Result<PathBuf> {
+    match &args.format_fallback {
+        Some(fallback) => save_with_fallback(img, output_path, output_format, fallback, metadata, args),
+        None => save_image(img, output_path, output_format, metadata, args).map(|_| output_path.to_path_buf()),
+    }
+}
+
+/// Coarse grid search for the largest JPEG that fits in `max_bytes`: dimensions shrink from
+/// `size` in 10% steps, and at each size the highest quality down to 50 that fits wins. Only
+/// when no size fits at quality 50 are lower qualities tried, smallest size first.
+fn fit_to_bytes(crop: &DynamicImage, size: (u32, u32), max_bytes: u64, args: &Cli) -> Result<(Vec<u8>, (u32, u32), u8)> {
+    const QUALITIES: &[u8] = &[90, 80, 70, 60, 50];
+    const LOW_QUALITIES: &[u8] = &[40, 30, 20, 10];
+    const SCALES: &[f64] = &[1.0, 0.9, 0.8, 0.7, 0.6, 0.5, 0.4, 0.3, 0.2, 0.1];
+
+    let encode = |img: &RgbImage, quality: u8| -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        JpegEncoder::new_with_quality(&mut bytes, quality)
+            .encode(img.as_raw(), img.width(), img.height(), ColorType::Rgb8)?;
+        Ok(bytes)
+    };
+    let resize = |scale: f64| {
+        let scaled = |dimension: u32| ((dimension as f64 * scale).round() as u32).max(1);
+        resize_in_steps(crop, scaled(size.0), scaled(size.1), args.scale_steps, 8).to_rgb8()
+    };
+
+    for &scale in SCALES {
+        let img = resize(scale);
+        for &quality in QUALITIES {
+            let bytes = encode(&img, quality)?;
+            if bytes.len() as u64 <= max_bytes {
+                return Ok((bytes, img.dimensions(), quality));
+            }
+        }
+    }
+
+    let smallest = resize(SCALES[SCALES.len() - 1]);
+    for &quality in LOW_QUALITIES {
+        let bytes = encode(&smallest, quality)?;
+        if bytes.len() as u64 <= max_bytes {
+            return Ok((bytes, smallest.dimensions(), quality));
+        }
+    }
+    Err(eyre!("No size and quality fits in {} bytes", max_bytes))
+}
+
+/// Resizes in `steps` passes, each scaling by the same factor, so large reductions are spread
+/// across several smaller ones instead of a single Lanczos pass. The result is RGBA at
+/// `bit_depth` bits per channel; float sources are scaled into the 16-bit range.
+fn resize_in_steps(img: &DynamicImage, width: u32, height: u32, steps: u32, bit_depth: u8) -> DynamicImage {
+    let (source_width, source_height) = img.dimensions();
+    let mut current = match bit_depth {
+        16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        _ => DynamicImage::ImageRgba8(img.to_rgba8()),
+    };
+
+    for step in 1..steps {
+        let progress = step as f64 / steps as f64;
+        let step_size = |source: u32, target: u32| {
+            let size = source as f64 * (target as f64 / source as f64).powf(progress);
+            (size.round() as u32).max(1)
+        };
+        current = current.resize_exact(
+            step_size(source_width, width),
+            step_size(source_height, height),
+            imageops::FilterType::Lanczos3,
+        );
+    }
+
+    current.resize_exact(width, height, imageops::FilterType::Lanczos3)
+}
+
+/// Multiplies alpha by a mask that fades to zero over `feather` pixels at the image edge, or at
+/// the edge of the inscribed circle when `circle` is set (with at least a pixel of antialiasing).
+fn apply_alpha_mask(img: &mut DynamicImage, feather: f32, circle: bool) {
+    let (width, height) = img.dimensions();
+    let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    let radius = center_x.min(center_y);
+    let feather = if circle { feather.max(1.0) } else { feather };
+
+    let coverage = |x: u32, y: u32| {
+        let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+        let inside = if circle {
+            radius - (px - center_x).hypot(py - center_y)
+        } else {
+            px.min(width as f32 - px).min(py).min(height as f32 - py)
+        };
+        if feather > 0.0 {
+            (inside / feather).clamp(0.0, 1.0)
+        } else if inside > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    };
+
+    if !matches!(img, DynamicImage::ImageRgba8(_) | DynamicImage::ImageRgba16(_)) {
+        *img = DynamicImage::ImageRgba8(img.to_rgba8());
+    }
+    match img {
+        DynamicImage::ImageRgba16(buffer) => {
+            for (x, y, pixel) in buffer.enumerate_pixels_mut() {
+                pixel[3] = (pixel[3] as f32 * coverage(x, y)).round() as u16;
+            }
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            for (x, y, pixel) in buffer.enumerate_pixels_mut() {
+                pixel[3] = (pixel[3] as f32 * coverage(x, y)).round() as u8;
+            }
+        }
+        _ => unreachable!("converted to RGBA above"),
+    }
+}
+
+/// Scales each color channel by the pixel's alpha, rounding to nearest.
+fn premultiply_alpha(img: &mut DynamicImage) {
+    match img {
+        DynamicImage::ImageRgba16(buffer) => {
+            for pixel in buffer.pixels_mut() {
+                let alpha = pixel[3] as u32;
+                for channel in &mut pixel.0[..3] {
+                    *channel = ((*channel as u32 * alpha + 32767) / 65535) as u16;
+                }
+            }
+        }
+        DynamicImage::ImageRgba8(buffer) => {
+            for pixel in buffer.pixels_mut() {
+                let alpha = pixel[3] as u32;
+                for channel in &mut pixel.0[..3] {
+                    *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+                }
+            }
+        }
+        other => {
+            *other = DynamicImage::ImageRgba8(other.to_rgba8());
+            premultiply_alpha(other);
+        }
+    }
+}
+
+/// Reduces the image to at most `colors` distinct colors with NeuQuant, optionally dithering.
+/// Returns the palette so the image can be stored as indexed color.
+fn quantize(mut img: RgbaImage, colors: u16, dither: bool) -> (RgbaImage, NeuQuant) {
+    let palette = NeuQuant::new(10, colors as usize, img.as_raw());
+    if dither {
+        imageops::dither(&mut img, &palette);
+    } else {
+        for pixel in img.pixels_mut() {
+            palette.map_color(pixel);
+        }
+    }
+    (img, palette)
+}
+
+fn encode_indexed_png(img: &RgbaImage, palette: &NeuQuant) -> Result<Vec<u8>> {
+    let colors = palette.color_map_rgba();
+    let rgb: Vec<u8> = colors.chunks_exact(4).flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let alpha: Vec<u8> = colors.chunks_exact(4).map(|c| c[3]).collect();
+    let indices: Vec<u8> = img.pixels().map(|p| palette.index_of(&p.0) as u8).collect();
+
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, img.width(), img.height());
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(rgb);
+    if alpha.iter().any(|&a| a != u8::MAX) {
+        encoder.set_trns(alpha);
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&indices)?;
+    writer.finish()?;
+
+    Ok(bytes)
+}
+
+/// The --size dimensions, scaled down proportionally when they exceed --max-output-megapixels.
+fn target_dimensions(args: &Cli) -> Result<(u32, u32)> {
+    let (width, height) = parse_dimensions(&args.size)?;
+    let Some(max_megapixels) = args.max_output_megapixels else {
+        return Ok((width, height));
+    };
+    let pixels = width as f64 * height as f64;
+    let max_pixels = max_megapixels * 1_000_000.0;
+    if pixels <= max_pixels {
+        return Ok((width, height));
+    }
+
+    let scale = (max_pixels / pixels).sqrt();
+    let scaled = |dimension: u32| ((dimension as f64 * scale).floor() as u32).max(1);
+    Ok((scaled(width), scaled(height)))
+}
+
+fn parse_dimensions(size: &str) -> Result<(u32, u32)> {
+    let dimensions: Vec<&str> = size.split('x').collect();
+    if dimensions.len() != 2 {
+        return Err(eyre!("Invalid size format. Expected format: widthxheight"));
+    }
+    let width: u32 = dimensions[0].parse()?;
+    let height: u32 = dimensions[1].parse()?;
+    if width == 0 || height == 0 {
+        return Err(eyre!("Invalid size {}: width and height must be greater than zero", size));
+    }
+    Ok((width, height))
+}
+
+fn parse_hex_color(color: &str) -> Result<Rgba<u8>> {
+    let hex = color.trim_start_matches('#');
+    if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(eyre!("Invalid color {}. Expected format: RRGGBB or RRGGBBAA", color));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(u8::MAX);
+    let alpha = if hex.len() == 8 { channel(6) } else { u8::MAX };
+    Ok(Rgba([channel(0), channel(2), channel(4), alpha]))
+}
+
+fn save_image(img: &DynamicImage, output_path: &Path, output_format: ImageFormat, metadata: &SourceMetadata, args: &Cli) -> Result<()> {
+    let jpeg_metadata = adds_jpeg_metadata(output_format, args);
+    if jpeg_metadata || s3::is_s3_url(output_path) {
+        let mut bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bytes), output_format)
+            .wrap_err_with(|| format!("Failed to encode resized image: {}", output_path.display()))?;
+        if jpeg_metadata {
+            bytes = add_jpeg_metadata(bytes, img, metadata, args)?;
+        }
+
+        return save_encoded(bytes, output_path, output_format, img.dimensions(), args.validate);
+    }
+
+    // Create the directory if it doesn't exist
+    if let Some(parent_dir) = output_path.parent() {
+        if !parent_dir.exists() {
+            fs::create_dir_all(parent_dir).wrap_err_with(|| format!("Failed to create directory: {}", parent_dir.display()))?;
+        }
+    }
+
+    // The default of 8 KiB matches the buffer save_with_format used before this was configurable
+    let save_err = || format!("Failed to save resized image: {}", output_path.display());
+    let file = fs::File::create(output_path).wrap_err_with(save_err)?;
+    let mut writer = BufWriter::with_capacity(args.write_buffer_kb as usize * 1024, file);
+    img.write_to(&mut writer, output_format).wrap_err_with(save_err)?;
+    writer.flush().wrap_err_with(save_err)?;
+    BYTES_WRITTEN.fetch_add(writer.stream_position().wrap_err_with(save_err)?, Ordering::Relaxed);
+
+    if args.validate {
+        validate_output(output_path, img.dimensions())?;
+    }
+
+    Ok(())
+}
+
+/// Encodes to `output_format` in memory first so an encoder failure can be told apart from a
+/// write failure, and on encoder failure saves as `fallback` with that extension instead.
+/// Returns the path that was written.
+fn save_with_fallback(img: &DynamicImage, output_path: &Path, output_format: ImageFormat, fallback: &str, metadata: &SourceMetadata, args: &Cli) -> Result<PathBuf> {
+    let mut bytes = Vec::new();
+    let Err(e) = img.write_to(&mut Cursor::new(&mut bytes), output_format) else {
+        if adds_jpeg_metadata(output_format, args) {
+            bytes = add_jpeg_metadata(bytes, img, metadata, args)?;
+        }
+        save_encoded(bytes, output_path, output_format, img.dimensions(), args.validate)?;
+        return Ok(output_path.to_path_buf());
+    };
+    save_fallback(img, output_path, fallback, e, metadata, args)
+}
+
+/// Saves `img` as `fallback` next to `output_path` after the primary encoder failed with `error`.
+fn save_fallback(img: &DynamicImage, output_path: &Path, fallback: &str, error: impl std::fmt::Display, metadata: &SourceMetadata, args: &Cli) -> Result<PathBuf> {
+    let fallback_format = determine_image_format(fallback)?;
+    let fallback_path = output_path.with_extension(fallback);
+    warn!("Encoding {} failed ({}), writing {} instead", output_path.display(), error, fallback_path.display());
+    if matches!(fallback_format, ImageFormat::Png | ImageFormat::Tiff) {
+        save_image(img, &fallback_path, fallback_format, metadata, args)?;
+    } else {
+        save_image(&DynamicImage::ImageRgba8(img.to_rgba8()), &fallback_path, fallback_format, metadata, args)?;
+    }
+    Ok(fallback_path)
+}
+
+/// Whether jpg outputs get metadata added after encoding, which the image encoders can't write.
+fn adds_jpeg_metadata(output_format: ImageFormat, args: &Cli) -> bool {
+    output_format == ImageFormat::Jpeg && (args.strip_but_keep_thumbnail || !args.keep_metadata.is_empty())
+}
+
+/// Adds the EXIF thumbnail for --strip-but-keep-thumbnail and the source metadata --keep-metadata
+/// selected to an encoded JPEG, right after its SOI marker or JFIF header.
+fn add_jpeg_metadata(jpeg: Vec<u8>, img: &DynamicImage, metadata: &SourceMetadata, args: &Cli) -> Result<Vec<u8>> {
+    let mut segments = Vec::new();
+    let thumbnail = if args.strip_but_keep_thumbnail { Some(img) } else { None };
+    if thumbnail.is_some() || metadata.orientation.is_some() {
+        segments.extend(exif_segment(thumbnail, metadata.orientation)?);
+    }
+    if let Some(profile) = &metadata.icc_profile {
+        segments.extend(icc_segments(profile));
+    }
+    if segments.is_empty() {
+        return Ok(jpeg);
+    }
+
+    if jpeg.get(..2) != Some(&[0xFF, 0xD8]) {
+        return Err(eyre!("Encoded jpg is missing its SOI marker"));
+    }
+    // JFIF wants its APP0 header straight after SOI, so the other segments go after it when present
+    let insert_at = match jpeg.get(2..6) {
+        Some(&[0xFF, 0xE0, high, low]) => 4 + u16::from_be_bytes([high, low]) as usize,
+        _ => 2,
+    }
+    .min(jpeg.len());
+
+    let mut out = Vec::with_capacity(jpeg.len() + segments.iter().map(Vec::len).sum::<usize>());
+    out.extend_from_slice(&jpeg[..insert_at]);
+    for segment in &segments {
+        out.extend_from_slice(segment);
+    }
+    out.extend_from_slice(&jpeg[insert_at..]);
+    Ok(out)
+}
+
+/// An APPn segment with the given marker, or None when the data doesn't fit in one segment.
+fn app_segment(marker: u8, header: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    // The segment length covers itself, the header and the data
+    let segment_len = u16::try_from(2 + header.len() + data.len()).ok()?;
+    let mut segment = Vec::with_capacity(segment_len as usize + 2);
+    segment.extend_from_slice(&[0xFF, marker]);
+    segment.extend_from_slice(&segment_len.to_be_bytes());
+    segment.extend_from_slice(header);
+    segment.extend_from_slice(data);
+    Some(segment)
+}
+
+/// An APP1 segment holding `orientation` and, when `thumbnail_of` is given, an EXIF thumbnail of
+/// it at most 160px on its longest side. A thumbnail that doesn't fit in the segment is left out
+/// with a warning.
+fn exif_segment(thumbnail_of: Option<&DynamicImage>, orientation: Option<u16>) -> Result<Option<Vec<u8>>> {
+    const THUMBNAIL_SIZE: u32 = 160;
+    let thumbnail = match thumbnail_of {
+        Some(img) => {
+            let preview = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8();
+            let mut thumbnail = Vec::new();
+            JpegEncoder::new_with_quality(&mut thumbnail, 75)
+                .encode(preview.as_raw(), preview.width(), preview.height(), ColorType::Rgb8)
+                .wrap_err("Failed to encode EXIF thumbnail")?;
+            Some(thumbnail)
+        }
+        None => None,
+    };
+
+    if let Some(segment) = app_segment(0xE1, b"Exif\0\0", &exif_tiff(thumbnail.as_deref(), orientation)?) {
+        return Ok(Some(segment));
+    }
+    warn!("EXIF thumbnail is too large for an APP1 segment, leaving it out");
+    match orientation {
+        Some(_) => Ok(app_segment(0xE1, b"Exif\0\0", &exif_tiff(None, orientation)?)),
+        None => Ok(None),
+    }
+}
+
+/// The TIFF structure of an EXIF block with `orientation` and the JPEG `thumbnail`, if any.
+fn exif_tiff(thumbnail: Option<&[u8]>, orientation: Option<u16>) -> Result<Vec<u8>> {
+    use exif::experimental::Writer;
+    use exif::{Field, In, Rational, Tag, Value};
+
+    // An IFD can't be written empty, so the primary one carries the resolution readers default to
+    let field = |tag, ifd_num, value| Field { tag, ifd_num, value };
+    let mut fields = vec![
+        field(Tag::XResolution, In::PRIMARY, Value::Rational(vec![Rational { num: 72, denom: 1 }])),
+        field(Tag::YResolution, In::PRIMARY, Value::Rational(vec![Rational { num: 72, denom: 1 }])),
+        field(Tag::ResolutionUnit, In::PRIMARY, Value::Short(vec![2])),
+    ];
+    if let Some(orientation) = orientation {
+        fields.push(field(Tag::Orientation, In::PRIMARY, Value::Short(vec![orientation])));
+    }
+    if thumbnail.is_some() {
+        fields.push(field(Tag::Compression, In::THUMBNAIL, Value::Short(vec![6])));
+    }
+
+    let mut writer = Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+    if let Some(thumbnail) = thumbnail {
+        writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false).map_err(|e| eyre!("Failed to write EXIF data: {}", e))?;
+    Ok(tiff.into_inner())
+}
+
+/// APP2 segments holding `profile`, split into chunks the way the ICC spec embeds it in JPEG.
+/// Profiles too large for the 255 chunks allowed are left out with a warning.
+fn icc_segments(profile: &[u8]) -> Vec<Vec<u8>> {
+    // What's left of a segment after its length, the "ICC_PROFILE\0" tag and the chunk numbering
+    const CHUNK_SIZE: usize = 65519;
+    let chunks: Vec<&[u8]> = profile.chunks(CHUNK_SIZE).collect();
+    let Ok(count) = u8::try_from(chunks.len()) else {
+        warn!("ICC profile is too large to embed in a jpg, leaving it out");
+        return Vec::new();
+    };
+    chunks.iter()
+        .zip(1..=count)
+        .filter_map(|(chunk, index)| {
+            let mut header = b"ICC_PROFILE\0".to_vec();
+            header.extend_from_slice(&[index, count]);
+            app_segment(0xE2, &header, chunk)
+        })
+        .collect()
+}
+
+/// Writes an already encoded image to disk or S3. With `validate`, local files are re-opened
+/// after writing, and S3 uploads, which can't be read back cheaply, are decoded from `bytes` first.
+fn save_encoded(bytes: Vec<u8>, output_path: &Path, output_format: ImageFormat, dimensions: (u32, u32), validate: bool) -> Result<()> {
+    let len = bytes.len() as u64;
+    if s3::is_s3_url(output_path) {
+        if validate {
+            let encoded = image::load_from_memory_with_format(&bytes, output_format)
+                .wrap_err_with(|| format!("Validation failed, output does not decode: {}", output_path.display()))?;
+            check_dimensions(output_path, encoded.dimensions(), dimensions)?;
+        }
+        s3::upload(output_path, bytes, output_format.to_mime_type())?;
+        BYTES_WRITTEN.fetch_add(len, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    if let Some(parent_dir) = output_path.parent() {
+        if !parent_dir.exists() {
+            fs::create_dir_all(parent_dir).wrap_err_with(|| format!("Failed to create directory: {}", parent_dir.display()))?;
+        }
+    }
+    fs::write(output_path, bytes)
+        .wrap_err_with(|| format!("Failed to save resized image: {}", output_path.display()))?;
+    BYTES_WRITTEN.fetch_add(len, Ordering::Relaxed);
+
+    if validate {
+        validate_output(output_path, dimensions)?;
+    }
+    Ok(())
+}
+
+fn validate_output(output_path: &Path, expected: (u32, u32)) -> Result<()> {
+    let written = image::open(output_path)
+        .wrap_err_with(|| format!("Validation failed, output does not decode: {}", output_path.display()))?;
+
+    check_dimensions(output_path, written.dimensions(), expected)
+}
+
+fn check_dimensions(output_path: &Path, actual: (u32, u32), expected: (u32, u32)) -> Result<()> {
+    if actual != expected {
+        let (width, height) = actual;
+        return Err(eyre!(
+            "Validation failed for {}: expected {}x{}, got {}x{}",
+            output_path.display(), expected.0, expected.1, width, height
+        ));
+    }
+
+    Ok(())
+}
+
+/// Slices the image into a grid of `tile_size` tiles, padding the right and bottom edges with `background`.
+fn save_tiles(img: &DynamicImage, original_path: &Path, file_stem: &str, tile_size: (u32, u32), background: Rgba<u8>, metadata: &SourceMetadata, args: &Cli) -> Result<()> {
+    let output_dir = output_dir(original_path, args);
+    let output_format = determine_image_format(&args.image_format)?;
+
+    let tiles: Vec<(u32, u32, DynamicImage)> = match img {
+        DynamicImage::ImageRgba16(buffer) => {
+            let background = Rgba(background.0.map(|c| c as u16 * 257));
+            split_tiles(buffer, tile_size, background).into_iter()
+                .map(|(col, row, tile)| (col, row, DynamicImage::ImageRgba16(tile)))
+                .collect()
+        }
+        _ => split_tiles(&img.to_rgba8(), tile_size, background).into_iter()
+            .map(|(col, row, tile)| (col, row, DynamicImage::ImageRgba8(tile)))
+            .collect(),
+    };
+
+    for (col, row, tile) in tiles {
+        let tile_path = output_dir.join(format!("{}_{}_{}.{}", file_stem, col, row, args.image_format));
+        let written_path = save_output(&tile, &tile_path, output_format, metadata, args)?;
+        record_dimensions(&written_path, tile.dimensions(), tile_size, args);
+    }
+
+    Ok(())
+}
+
+type Buffer<P> = ImageBuffer<P, Vec<<P as Pixel>::Subpixel>>;
+
+fn split_tiles<P: Pixel>(img: &Buffer<P>, tile_size: (u32, u32), background: P) -> Vec<(u32, u32, Buffer<P>)> {
+    let (tile_width, tile_height) = tile_size;
+    let columns = img.width().div_ceil(tile_width);
+    let rows = img.height().div_ceil(tile_height);
+
+    let mut tiles = Vec::with_capacity((columns * rows) as usize);
+    for row in 0..rows {
+        for col in 0..columns {
+            let x = col * tile_width;
+            let y = row * tile_height;
+            let view = imageops::crop_imm(img, x, y, tile_width.min(img.width() - x), tile_height.min(img.height() - y));
+
+            let mut tile = ImageBuffer::from_pixel(tile_width, tile_height, background);
+            imageops::replace(&mut tile, &*view, 0, 0);
+            tiles.push((col, row, tile));
+        }
+    }
+
+    tiles
+}
+
+/// Chooses the supported output format the Accept header prefers most, honoring q-values.
+/// Each format takes the q of its most specific matching media range, and ties go to the
+/// range listed earlier, then to the OUTPUT_FORMATS order.
+fn negotiate_format(accept: &str) -> Option<&'static str> {
+    let ranges: Vec<(String, f32)> = accept.split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().filter(|m| !m.is_empty())?.to_ascii_lowercase();
+            let q = parts
+                .find_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q=")))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((media_type, q.clamp(0.0, 1.0)))
+        })
+        .collect();
+
+    let specificity = |range: &str, mime: &str| -> Option<u8> {
+        let (group, _) = mime.split_once('/')?;
+        match range.split_once('/') {
+            _ if range == mime => Some(2),
+            Some((range_group, "*")) if range_group == group => Some(1),
+            Some(("*", "*")) => Some(0),
+            _ => None,
+        }
+    };
+
+    OUTPUT_FORMATS.iter()
+        .enumerate()
+        .filter_map(|(rank, (name, mime))| {
+            // Most specific matching range wins, regardless of its q
+            let (position, q, specific) = ranges.iter()
+                .enumerate()
+                .filter_map(|(position, (range, q))| Some((position, *q, specificity(range, mime)?)))
+                .max_by_key(|(position, _, specific)| (*specific, std::cmp::Reverse(*position)))?;
+            (q > 0.0).then_some((q, specific, position, rank, *name))
+        })
+        .max_by(|a, b| {
+            a.0.total_cmp(&b.0)
+                .then(a.1.cmp(&b.1))
+                .then(b.2.cmp(&a.2))
+                .then(b.3.cmp(&a.3))
+        })
+        .map(|(_, _, _, _, name)| name)
+}
+
+fn determine_image_format(image_format: &str) -> Result<ImageFormat> {
+    match image_format.to_lowercase().as_str() {
+        "png" | "apng" => Ok(ImageFormat::Png),
+        "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
+        "gif" => Ok(ImageFormat::Gif),
+        "bmp" => Ok(ImageFormat::Bmp),
+        "tiff" => Ok(ImageFormat::Tiff),
+        "webp" => Ok(ImageFormat::WebP),
+        _ => Err(eyre!("Unsupported format: {}", image_format))
+    }
+}
+
+fn parse_percentage(value: &str) -> std::result::Result<f32, String> {
+    let pct: f32 = value.parse().map_err(|_| format!("{} is not a number", value))?;
+    if pct > 0.0 && pct <= 100.0 {
+        Ok(pct)
+    } else {
+        Err(format!("{} is not in the range (0, 100]", value))
+    }
+}
+
+fn parse_focus(value: &str) -> std::result::Result<(f32, f32), String> {
+    let (x, y) = value.split_once(',').ok_or_else(|| format!("{} is not in the format x,y", value))?;
+    Ok((parse_fraction(x.trim())?, parse_fraction(y.trim())?))
+}
+
+fn parse_megapixels(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(megapixels) if megapixels.is_finite() && megapixels > 0.0 => Ok(megapixels),
+        _ => Err(format!("{} is not a finite number greater than zero", value)),
+    }
+}
+
+fn parse_fraction(value: &str) -> std::result::Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("{} is not a number between 0 and 1", value)),
+    }
+}
+
+fn parse_padding(value: &str) -> std::result::Result<f32, String> {
+    let pct: f32 = value.parse().map_err(|_| format!("{} is not a number", value))?;
+    if pct >= 0.0 && pct.is_finite() {
+        Ok(pct)
+    } else {
+        Err(format!("{} is not a non-negative percentage", value))
+    }
+}
+
+fn parse_bit_depth(value: &str) -> std::result::Result<u8, String> {
+    match value {
+        "8" => Ok(8),
+        "16" => Ok(16),
+        _ => Err(format!("{} is not a supported bit depth (8 or 16)", value)),
+    }
+}
+
+fn parse_bias(value: &str) -> std::result::Result<f32, String> {
+    let bias: f32 = value.parse().map_err(|_| format!("{} is not a number", value))?;
+    if (-0.5..=0.5).contains(&bias) {
+        Ok(bias)
+    } else {
+        Err(format!("{} is not in the range [-0.5, 0.5]", value))
+    }
+}
+
+/// A detector that can be handed between worker threads.
+struct PooledDetector(Box<dyn Detector>);
+
+// SAFETY: create_detector_with_model always returns rustface's FuStDetector, whose state is the
+// Model plus owned Vec buffers. The boxed trait object only lacks a Send bound, and a checked-out
+// detector is used by one thread at a time.
+unsafe impl Send for PooledDetector {}
+
+/// A fixed set of detectors built once from the parsed model. Workers check one out per image
+/// and block while all of them are in use.
+struct DetectorPool {
+    available: Mutex<Vec<PooledDetector>>,
+    returned: Condvar,
+    model: Model,
+    params: DetectorParams,
+}
+
+impl DetectorPool {
+    fn new(size: usize, params: &DetectorParams) -> Result<Self> {
+        let model = rustface::read_model(Cursor::new(MODEL_DATA))
+            .wrap_err("Failed to read the model from bytes")?;
+        let detectors = (0..size.max(1))
+            .map(|_| PooledDetector(create_detector(model.clone(), params)))
+            .collect();
+
+        Ok(DetectorPool { available: Mutex::new(detectors), returned: Condvar::new(), model, params: *params })
+    }
+
+    /// Runs detection, turning a detector panic into an error. The detector that panicked may be
+    /// left in a bad state, so it is replaced with a fresh one.
+    fn detect(&self, img: &DynamicImage) -> Result<Vec<FaceInfo>> {
+        self.detect_with(img, &self.params)
+    }
+
+    /// Like `detect`, but with `params` in place of the pool's settings for this one run.
+    fn detect_with(&self, img: &DynamicImage, params: &DetectorParams) -> Result<Vec<FaceInfo>> {
+        let mut checkout = self.checkout();
+        let detector = checkout.detector.as_mut().expect("detector is only taken on drop");
+        let run = || {
+            configure_detector(detector.0.as_mut(), params);
+            let faces = run_detector(detector.0.as_mut(), img);
+            configure_detector(detector.0.as_mut(), &self.params);
+            faces
+        };
+        match panic::catch_unwind(AssertUnwindSafe(run)) {
+            Ok(faces) => Ok(faces),
+            Err(payload) => {
+                *detector = PooledDetector(create_detector(self.model.clone(), &self.params));
+                let message = payload.downcast_ref::<&str>().map(|m| m.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Err(eyre!("Face detector panicked: {}", message))
+            }
+        }
+    }
+
+    fn checkout(&self) -> Checkout<'_> {
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(detector) = available.pop() {
+                return Checkout { pool: self, detector: Some(detector) };
+            }
+            available = self.returned.wait(available).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Returns the detector to its pool when dropped.
+struct Checkout<'a> {
+    pool: &'a DetectorPool,
+    detector: Option<PooledDetector>,
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        if let Some(detector) = self.detector.take() {
+            self.pool.available.lock().unwrap_or_else(|e| e.into_inner()).push(detector);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+fn create_detector(model: Model, params: &DetectorParams) -> Box<dyn Detector> {
+    let mut detector = rustface::create_detector_with_model(model);
+    configure_detector(detector.as_mut(), params);
+    detector
+}
+
+fn configure_detector(detector: &mut dyn Detector, params: &DetectorParams) {
+    detector.set_min_face_size(params.min_face_size);
+    detector.set_score_thresh(params.score_thresh);
+    detector.set_pyramid_scale_factor(params.pyramid_scale_factor);
+    detector.set_slide_window_step(params.slide_window_step.0, params.slide_window_step.1);
+}
+
+fn run_detector(detector: &mut dyn Detector, img: &DynamicImage) -> Vec<FaceInfo> {
+    let (width, height) = img.dimensions();
+    let gray_img = img.to_luma8();
+    let bytes = gray_img.into_raw();
+    let image = ImageData::new(&bytes, width, height);
+
+    detector.detect(&image)
+}
+
+fn detect_faces_with(img: &DynamicImage, model_data: &[u8], params: &DetectorParams) -> Result<Vec<FaceInfo>> {
+    let model_instance = rustface::read_model(Cursor::new(model_data))
+        .wrap_err("Failed to read the model from bytes")?;
+
+    let mut detector = create_detector(model_instance, params);
+    Ok(run_detector(detector.as_mut(), img))
+}
+
+/// Intersection over union of two bounding boxes, 0.0 when they don't overlap.
+fn iou(a: &Rectangle, b: &Rectangle) -> f64 {
+    let left = a.x().max(b.x()) as i64;
+    let top = a.y().max(b.y()) as i64;
+    let right = (a.x() as i64 + a.width() as i64).min(b.x() as i64 + b.width() as i64);
+    let bottom = (a.y() as i64 + a.height() as i64).min(b.y() as i64 + b.height() as i64);
+
+    let intersection = (right - left).max(0) * (bottom - top).max(0);
+    let union = a.width() as i64 * a.height() as i64 + b.width() as i64 * b.height() as i64 - intersection;
+    if union <= 0 {
+        0.0
+    } else {
+        intersection as f64 / union as f64
+    }
+}
+
+/// A crop rectangle in source image pixels, kept separate from the pixels so the same crop can be
+/// applied to every frame of an animation.
+#[derive(Clone, Copy, Debug)]
+struct CropWindow {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl CropWindow {
+    fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        img.crop_imm(self.x, self.y, self.width, self.height)
+    }
+}
+
+/// With --thorough-detect, adds faces from a second pass when `faces` has fewer than
+/// --thorough-min-faces. rustface won't look for faces under 20px, so the pass runs on a 2x
+/// upscale with half the slide step; faces overlapping a first-pass face are dropped.
+fn detect_small_faces(detectors: &DetectorPool, img: &DynamicImage, mut faces: Vec<FaceInfo>, args: &Cli) -> Vec<FaceInfo> {
+    if !args.thorough_detect || faces.len() >= args.thorough_min_faces {
+        return faces;
+    }
+
+    let (width, height) = img.dimensions();
+    let upscaled = img.resize_exact(width * 2, height * 2, imageops::FilterType::Triangle);
+    let params = DetectorParams {
+        slide_window_step: ((detectors.params.slide_window_step.0 / 2).max(1), (detectors.params.slide_window_step.1 / 2).max(1)),
+        ..detectors.params
+    };
+    let small_faces = match detectors.detect_with(&upscaled, &params) {
+        Ok(small_faces) => small_faces,
+        Err(e) => {
+            warn!("{} in the thorough pass, keeping the first pass faces", e);
+            return faces;
+        }
+    };
+
+    for mut face in small_faces {
+        let bbox = face.bbox_mut();
+        *bbox = Rectangle::new(bbox.x() / 2, bbox.y() / 2, (bbox.width() / 2).max(1), (bbox.height() / 2).max(1));
+        if faces.iter().all(|existing| iou(existing.bbox(), face.bbox()) < 0.3) {
+            faces.push(face);
+        }
+    }
+    faces
+}
+
+/// Detects faces, and with --detect-flip-180 returns the image rotated upright when the
+/// rotated copy yields the higher-scoring face, along with whether it was rotated.
+fn detect_oriented(img: DynamicImage, img_path: &Path, detectors: &DetectorPool, args: &Cli) -> Result<(DynamicImage, Vec<FaceInfo>, bool)> {
+    // A detector panic costs the face crop, not the image, unless --no-panic-fallback is set
+    let detect = |img: &DynamicImage| match detectors.detect(img).map(|faces| detect_small_faces(detectors, img, faces, args)) {
+        Err(e) if !args.no_panic_fallback => {
+            warn!("{} on {}, using a center crop instead", e, img_path.display());
+            Ok(Vec::new())
+        }
+        result => result,
+    };
+
+    let faces = detect(&img)?;
+    if !args.detect_flip_180 {
+        return Ok((img, faces, false));
+    }
+
+    let flipped = img.rotate180();
+    let flipped_faces = detect(&flipped)?;
+    let best_score = |faces: &[FaceInfo]| faces.iter().map(FaceInfo::score).fold(f64::NEG_INFINITY, f64::max);
+    if best_score(&flipped_faces) > best_score(&faces) {
+        Ok((flipped, flipped_faces, true))
+    } else {
+        Ok((img, faces, false))
+    }
+}
+
+fn face_gravity_crop(img: &DynamicImage, faces: &[FaceInfo], args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+
+    if let Some(face) = faces.first() {
+        let bbox = face.bbox();
+        if [args.pad_top, args.pad_bottom, args.pad_left, args.pad_right].iter().any(Option::is_some) {
+            return padded_face_crop(img, bbox, args);
+        }
+        let dimension = match args.face_fill_pct {
+            Some(pct) => face_fill_dimension(bbox.width().max(bbox.height()), pct, width.min(height)),
+            None => width.min(height),
+        };
+        let face_center_x = bbox.x() as i64 + (bbox.width() / 2) as i64;
+        let face_center_y = bbox.y() as i64 + (bbox.height() / 2) as i64;
+
+        crop_square(img, blend_center(img, (face_center_x, face_center_y), args), dimension, args)
+    } else {
+        center_crop(img, args)
+    }
+}
+
+/// Interpolates from the image center (--gravity-blend 0.0) to the face `center` (1.0).
+fn blend_center(img: &DynamicImage, center: (i64, i64), args: &Cli) -> (i64, i64) {
+    let (width, height) = img.dimensions();
+    let blend = |face: i64, middle: u32| {
+        let middle = (middle / 2) as f32;
+        (middle + args.gravity_blend * (face as f32 - middle)).round() as i64
+    };
+    (blend(center.0, width), blend(center.1, height))
+}
+
+/// Square crop around the face grown by the --pad-* percentages on each side, centered on the
+/// padded box so the larger pads get the extra room. With --face-fill-pct the crop is at least
+/// the fill size; either way it is capped at the image's shorter side and clamped to the image.
+fn padded_face_crop(img: &DynamicImage, bbox: &Rectangle, args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+    let face_size = bbox.width().max(bbox.height()) as f32;
+    let pad = |pct: Option<f32>| (pct.unwrap_or(0.0) / 100.0 * face_size).round() as i64;
+
+    let left = bbox.x() as i64 - pad(args.pad_left);
+    let right = bbox.x() as i64 + bbox.width() as i64 + pad(args.pad_right);
+    let top = bbox.y() as i64 - pad(args.pad_top);
+    let bottom = bbox.y() as i64 + bbox.height() as i64 + pad(args.pad_bottom);
+
+    let fill = args.face_fill_pct.map_or(0, |pct| face_fill_dimension(face_size as u32, pct, width.min(height)));
+    let dimension = ((right - left).max(bottom - top).max(fill as i64)).clamp(1, width.min(height) as i64) as u32;
+    crop_square(img, blend_center(img, ((left + right) / 2, (top + bottom) / 2), args), dimension, args)
+}
+
+/// Groups faces whose centers lie within `distance` face sizes of any other face in the group.
+fn cluster_faces(faces: &[FaceInfo], distance: f32) -> Vec<Vec<&FaceInfo>> {
+    let center = |bbox: &Rectangle| {
+        (bbox.x() as f32 + bbox.width() as f32 / 2.0, bbox.y() as f32 + bbox.height() as f32 / 2.0)
+    };
+    let near = |a: &FaceInfo, b: &FaceInfo| {
+        let (ax, ay) = center(a.bbox());
+        let (bx, by) = center(b.bbox());
+        let size = a.bbox().width().max(a.bbox().height()).max(b.bbox().width()).max(b.bbox().height());
+        (ax - bx).hypot(ay - by) <= distance * size as f32
+    };
+
+    // Single-linkage clustering: flood-fill from each unassigned face
+    let mut cluster_of: Vec<Option<usize>> = vec![None; faces.len()];
+    let mut clusters: Vec<Vec<&FaceInfo>> = Vec::new();
+    for start in 0..faces.len() {
+        if cluster_of[start].is_some() {
+            continue;
+        }
+        let index = clusters.len();
+        cluster_of[start] = Some(index);
+        let mut members = vec![start];
+        let mut next = 0;
+        while next < members.len() {
+            let current = members[next];
+            for other in 0..faces.len() {
+                if cluster_of[other].is_none() && near(&faces[current], &faces[other]) {
+                    cluster_of[other] = Some(index);
+                    members.push(other);
+                }
+            }
+            next += 1;
+        }
+        clusters.push(members.into_iter().map(|i| &faces[i]).collect());
+    }
+
+    clusters
+}
+
+/// Square crop centered on the union of the cluster's bboxes, with a margin of one average
+/// face size on each side, capped at the image's shorter side.
+fn cluster_crop(img: &DynamicImage, cluster: &[&FaceInfo], args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+    let left = cluster.iter().map(|f| f.bbox().x() as i64).min().unwrap_or(0);
+    let top = cluster.iter().map(|f| f.bbox().y() as i64).min().unwrap_or(0);
+    let right = cluster.iter().map(|f| f.bbox().x() as i64 + f.bbox().width() as i64).max().unwrap_or(0);
+    let bottom = cluster.iter().map(|f| f.bbox().y() as i64 + f.bbox().height() as i64).max().unwrap_or(0);
+
+    let face_size = cluster.iter().map(|f| f.bbox().width().max(f.bbox().height()) as i64).sum::<i64>()
+        / cluster.len().max(1) as i64;
+    let dimension = ((right - left).max(bottom - top) + 2 * face_size).clamp(1, width.min(height) as i64) as u32;
+
+    crop_square(img, ((left + right) / 2, (top + bottom) / 2), dimension, args)
+}
+
+/// Crops a `dimension` square around `center` shifted by the crop bias, keeping the
+/// window inside the image so the crop stays square.
+fn crop_square(img: &DynamicImage, center: (i64, i64), dimension: u32, args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+    let center_x = center.0 + (args.crop_bias_x * dimension as f32).round() as i64;
+    let center_y = center.1 + (args.crop_bias_y * dimension as f32).round() as i64;
+
+    let x = (center_x - (dimension / 2) as i64).clamp(0, (width - dimension) as i64) as u32;
+    let y = (center_y - (dimension / 2) as i64).clamp(0, (height - dimension) as i64) as u32;
+    CropWindow { x, y, width: dimension, height: dimension }
+}
+
+/// The largest window with the `aspect` ratio that fits in the image, around `center` shifted by
+/// the crop bias and kept inside the image, so resizing it to the output size doesn't stretch it.
+fn crop_to_aspect(img: &DynamicImage, center: (i64, i64), aspect: (u32, u32), args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+    let (aspect_width, aspect_height) = (aspect.0.max(1) as u64, aspect.1.max(1) as u64);
+    let (crop_width, crop_height) = if width as u64 * aspect_height > height as u64 * aspect_width {
+        (((height as u64 * aspect_width) / aspect_height).max(1) as u32, height)
+    } else {
+        (width, ((width as u64 * aspect_height) / aspect_width).max(1) as u32)
+    };
+    let center_x = center.0 + (args.crop_bias_x * crop_width as f32).round() as i64;
+    let center_y = center.1 + (args.crop_bias_y * crop_height as f32).round() as i64;
+
+    let x = (center_x - (crop_width / 2) as i64).clamp(0, (width - crop_width) as i64) as u32;
+    let y = (center_y - (crop_height / 2) as i64).clamp(0, (height - crop_height) as i64) as u32;
+    CropWindow { x, y, width: crop_width, height: crop_height }
+}
+
+/// Side of the square crop in which a face of `face_size` pixels spans `pct` percent,
+/// never larger than the image's shorter side.
+fn face_fill_dimension(face_size: u32, pct: f32, max_dimension: u32) -> u32 {
+    if face_size > max_dimension {
+        warn!("Face is larger than the image's shorter side, using the tightest crop available instead of {}% fill", pct);
+        return max_dimension;
+    }
+    let dimension = (face_size as f32 * 100.0 / pct).ceil() as u32;
+    dimension.clamp(face_size.max(1), max_dimension)
+}
+
+fn center_crop(img: &DynamicImage, args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+    let dimension = width.min(height);
+    crop_square(img, ((width / 2) as i64, (height / 2) as i64), dimension, args)
+}
+
+/// The largest crop at the `aspect` of the output size centered on a focal point given as
+/// fractions of the width and height, clamped to the image.
+fn focus_crop(img: &DynamicImage, focus: (f32, f32), aspect: (u32, u32), args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+    let center = ((focus.0 * width as f32).round() as i64, (focus.1 * height as f32).round() as i64);
+    crop_to_aspect(img, center, aspect, args)
+}
+
+/// The largest crop at the `aspect` of the output size centered on the luminance-weighted center
+/// of mass, which tends to land on the subject of high-key photos. Falls back to the image center
+/// when the image is black.
+fn brightness_crop(img: &DynamicImage, aspect: (u32, u32), args: &Cli) -> CropWindow {
+    let (width, height) = img.dimensions();
+    let (mut total, mut sum_x, mut sum_y) = (0.0f64, 0.0f64, 0.0f64);
+    for (x, y, pixel) in img.to_luma8().enumerate_pixels() {
+        let weight = pixel.0[0] as f64;
+        total += weight;
+        sum_x += weight * x as f64;
+        sum_y += weight * y as f64;
+    }
+
+    let center = if total > 0.0 {
+        ((sum_x / total).round() as i64, (sum_y / total).round() as i64)
+    } else {
+        ((width / 2) as i64, (height / 2) as i64)
+    };
+    crop_to_aspect(img, center, aspect, args)
+}
+
+/// Lowercases `value`, folds common Latin accented letters to ASCII and joins the remaining runs
+/// of letters and digits with single hyphens. Names with nothing left become "image".
+fn slugify(value: &str) -> String {
+    let mut slug = String::new();
+    let mut pending_hyphen = false;
+    for c in value.chars().flat_map(char::to_lowercase) {
+        let folded = match c {
+            'a'..='z' | '0'..='9' => None,
+            'à'..='å' | 'ā' | 'ă' | 'ą' => Some("a"),
+            'æ' => Some("ae"),
+            'ç' | 'ć' | 'č' => Some("c"),
+            'ď' | 'đ' | 'ð' => Some("d"),
+            'è'..='ë' | 'ē' | 'ė' | 'ę' | 'ě' => Some("e"),
+            'ğ' => Some("g"),
+            'ì'..='ï' | 'ī' | 'į' | 'ı' => Some("i"),
+            'ł' | 'ľ' => Some("l"),
+            'ñ' | 'ń' | 'ň' => Some("n"),
+            'ò'..='ö' | 'ø' | 'ō' | 'ő' => Some("o"),
+            'œ' => Some("oe"),
+            'ř' => Some("r"),
+            'ß' => Some("ss"),
+            'ś' | 'š' | 'ş' => Some("s"),
+            'ť' | 'ţ' => Some("t"),
+            'þ' => Some("th"),
+            'ù'..='ü' | 'ū' | 'ů' | 'ű' | 'ų' => Some("u"),
+            'ý' | 'ÿ' => Some("y"),
+            'ź' | 'ż' | 'ž' => Some("z"),
+            _ => {
+                pending_hyphen = !slug.is_empty();
+                continue;
+            }
+        };
+        if pending_hyphen {
+            slug.push('-');
+            pending_hyphen = false;
+        }
+        match folded {
+            Some(ascii) => slug.push_str(ascii),
+            None => slug.push(c),
+        }
+    }
+
+    if slug.is_empty() {
+        slug.push_str("image");
+    }
+    slug
+}
+
+/// Whether the file's EXIF data has both a GPS latitude and longitude. Files without readable
+/// EXIF data count as having none.
+fn has_gps(path: &Path) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    match exif::Reader::new().read_from_container(&mut std::io::BufReader::new(file)) {
+        Ok(exif) => [exif::Tag::GPSLatitude, exif::Tag::GPSLongitude].iter()
+            .all(|tag| exif.get_field(*tag, exif::In::PRIMARY).is_some()),
+        Err(_) => false,
+    }
+}
+
+/// The source metadata --keep-metadata carries over to jpg outputs. Kinds that weren't asked for,
+/// or that the source doesn't have, are None.
+#[derive(Default)]
+struct SourceMetadata {
+    /// The EXIF orientation, 1 to 8
+    orientation: Option<u16>,
+    icc_profile: Option<Vec<u8>>,
+}
+
+impl SourceMetadata {
+    fn read(path: &Path, args: &Cli) -> SourceMetadata {
+        let keep = |kind| args.keep_metadata.contains(&kind);
+        SourceMetadata {
+            orientation: if keep(MetadataKind::Orientation) { read_orientation(path) } else { None },
+            icc_profile: if keep(MetadataKind::Icc) { read_icc_profile(path) } else { None },
+        }
+    }
+
+    /// Updates the orientation for pixels that were rotated 180 degrees, so viewers still show
+    /// the image the way the source was shown.
+    fn rotate180(&mut self) {
+        self.orientation = self.orientation.map(|orientation| match orientation {
+            1 => 3,
+            3 => 1,
+            2 => 4,
+            4 => 2,
+            5 => 7,
+            7 => 5,
+            6 => 8,
+            8 => 6,
+            other => other,
+        });
+    }
+}
+
+fn read_orientation(path: &Path) -> Option<u16> {
+    let file = fs::File::open(path).ok()?;
+    let exif = exif::Reader::new().read_from_container(&mut std::io::BufReader::new(file)).ok()?;
+    let orientation = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)?;
+    u16::try_from(orientation).ok().filter(|orientation| (1..=8).contains(orientation))
+}
+
+/// Reads the embedded ICC profile of jpg, png and tiff files.
+fn read_icc_profile(path: &Path) -> Option<Vec<u8>> {
+    use image::ImageDecoder;
+
+    let reader = std::io::BufReader::new(fs::File::open(path).ok()?);
+    match ImageFormat::from_path(path).ok()? {
+        ImageFormat::Jpeg => image::codecs::jpeg::JpegDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Png => image::codecs::png::PngDecoder::new(reader).ok()?.icc_profile(),
+        ImageFormat::Tiff => image::codecs::tiff::TiffDecoder::new(reader).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+fn file_stem(path: &Path) -> Result<String> {
+    let file_stem = path.file_stem()
+        .ok_or_else(|| eyre!("Failed to get the file stem for: {}", path.display()))?;
+    Ok(file_stem.to_string_lossy().to_string())
+}
+
+/// The folder outputs are written to: --output-path, or next to the original.
+fn output_dir(original_path: &Path, args: &Cli) -> PathBuf {
+    match &args.output_path {
+        Some(dir) => dir.clone(),
+        None if original_path.is_dir() => original_path.to_path_buf(),
+        None => original_path.parent().unwrap_or_else(|| Path::new(".")).to_path_buf(),
+    }
+}
+
+fn determine_output_path(original_path: &Path, file_stem: &str, args: &Cli) -> PathBuf {
+    // Sequential names replace the original stem outright, so they don't get the suffix
+    let suffix = if args.sequential_names { "" } else { "_resized" };
+    // APNG files keep the .png extension so viewers without APNG support still show the first frame
+    let extension = if args.image_format.eq_ignore_ascii_case("apng") { "png" } else { args.image_format.as_str() };
+    output_dir(original_path, args).join(format!("{}{}.{}", file_stem, suffix, extension))
 }
 
@@ -0,0 +1,83 @@
+//! The `probe` subcommand: reads only the header of each image to report its dimensions and
+//! format, without decoding any pixels.
+
+use clap::Args;
+use eyre::{eyre, Result, WrapErr};
+use image::io::Reader;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+
+#[derive(Args)]
+pub struct ProbeArgs {
+    /// The image or folder of images to probe.
+    img_path: PathBuf,
+    /// Print the results as JSON instead of one line per image
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct ImageProbe {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    format: &'static str,
+}
+
+pub fn run(args: &ProbeArgs) -> Result<()> {
+    let paths = if args.img_path.is_dir() {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&args.img_path)
+            .wrap_err_with(|| format!("Failed to read directory: {}", args.img_path.display()))?
+            .filter_map(|entry| match entry {
+                Ok(entry) => Some(entry.path()),
+                Err(e) => {
+                    error!("Failed to read directory entry: {}", e);
+                    None
+                }
+            })
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        paths
+    } else if args.img_path.exists() {
+        vec![args.img_path.clone()]
+    } else {
+        return Err(eyre!("The provided path does not exist: {}", args.img_path.display()));
+    };
+
+    let probes: Vec<ImageProbe> = paths.iter()
+        .filter_map(|path| match probe(path) {
+            Ok(probe) => Some(probe),
+            Err(e) => {
+                warn!("Skipping unsupported or broken file: {}: {:#}", path.display(), e);
+                None
+            }
+        })
+        .collect();
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&probes)?);
+    } else {
+        for probe in &probes {
+            println!("{} {}x{} {}", probe.path.display(), probe.width, probe.height, probe.format);
+        }
+    }
+
+    Ok(())
+}
+
+/// Guesses the format from the file's magic bytes and reads the dimensions from its header.
+fn probe(path: &Path) -> Result<ImageProbe> {
+    let reader = Reader::open(path)?.with_guessed_format()?;
+    let format = reader.format().ok_or_else(|| eyre!("Unrecognized image format"))?;
+    let (width, height) = reader.into_dimensions()?;
+
+    Ok(ImageProbe {
+        path: path.to_path_buf(),
+        width,
+        height,
+        format: format.extensions_str().first().copied().unwrap_or("unknown"),
+    })
+}
@@ -0,0 +1,83 @@
+//! Optional upload of outputs to S3, enabled with the `s3` feature.
+
+use eyre::{eyre, Result};
+use std::path::Path;
+
+const SCHEME: &str = "s3://";
+
+pub fn is_s3_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| p.starts_with(SCHEME))
+}
+
+/// Splits `s3://bucket/key` into its bucket and key.
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn split_url(path: &Path) -> Result<(&str, &str)> {
+    let url = path.to_str()
+        .and_then(|p| p.strip_prefix(SCHEME))
+        .ok_or_else(|| eyre!("Not an S3 URL: {}", path.display()))?;
+
+    match url.split_once('/') {
+        Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok((bucket, key)),
+        _ => Err(eyre!("Expected an S3 URL of the form s3://bucket/key, got: {}", path.display())),
+    }
+}
+
+#[cfg(feature = "s3")]
+mod client {
+    use super::split_url;
+    use eyre::{eyre, Result, WrapErr};
+    use std::path::Path;
+    use std::sync::OnceLock;
+
+    struct Uploader {
+        runtime: tokio::runtime::Runtime,
+        client: aws_sdk_s3::Client,
+    }
+
+    static UPLOADER: OnceLock<Uploader> = OnceLock::new();
+
+    pub fn init() -> Result<()> {
+        if UPLOADER.get().is_some() {
+            return Ok(());
+        }
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .wrap_err("Failed to start the S3 runtime")?;
+        let config = runtime.block_on(aws_config::load_defaults(aws_config::BehaviorVersion::latest()));
+        let client = aws_sdk_s3::Client::new(&config);
+
+        let _ = UPLOADER.set(Uploader { runtime, client });
+        Ok(())
+    }
+
+    pub fn upload(path: &Path, bytes: Vec<u8>, content_type: &str) -> Result<()> {
+        let uploader = UPLOADER.get().ok_or_else(|| eyre!("S3 client was not initialized"))?;
+        let (bucket, key) = split_url(path)?;
+
+        uploader.runtime.block_on(
+            uploader.client.put_object()
+                .bucket(bucket)
+                .key(key)
+                .content_type(content_type)
+                .body(bytes.into())
+                .send()
+        ).wrap_err_with(|| format!("Failed to upload {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "s3")]
+pub use client::{init, upload};
+
+#[cfg(not(feature = "s3"))]
+pub fn init() -> Result<()> {
+    Err(eyre!("S3 output requires imgrszr to be built with `--features s3`"))
+}
+
+#[cfg(not(feature = "s3"))]
+pub fn upload(path: &Path, _bytes: Vec<u8>, _content_type: &str) -> Result<()> {
+    Err(eyre!("Cannot upload {}: imgrszr was built without the `s3` feature", path.display()))
+}
@@ -0,0 +1,139 @@
+//! The `score-histogram` subcommand: runs face detection over a folder and prints how the face
+//! scores are distributed, to help pick a `score-thresh` that separates faces from noise.
+//! Nothing is resized.
+
+use crate::{detect_faces_with, DetectorParams, MODEL_DATA};
+use clap::Args;
+use eyre::{eyre, Result, WrapErr};
+use rayon::prelude::*;
+use rustface::FaceInfo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use tracing::{error, warn};
+
+#[derive(Args)]
+pub struct ScoreHistogramArgs {
+    /// The folder of images to run detection on.
+    img_path: PathBuf,
+    /// Detector settings, in the same format as diff-faces. The score threshold defaults to 0.1 so
+    /// weak detections show up in the histogram.
+    #[clap(long, default_value = "score-thresh=0.1")]
+    detector: DetectorParams,
+    /// Width of each score bucket
+    #[clap(long, default_value_t = 1.0)]
+    bucket_width: f64,
+    /// Print the histogram as JSON instead of bars
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Histogram {
+    images: usize,
+    images_without_faces: usize,
+    faces: usize,
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Serialize)]
+struct Bucket {
+    /// Inclusive lower bound of the bucket's scores
+    min: f64,
+    /// Exclusive upper bound of the bucket's scores
+    max: f64,
+    count: usize,
+}
+
+pub fn run(args: &ScoreHistogramArgs) -> Result<()> {
+    if !args.bucket_width.is_finite() || args.bucket_width <= 0.0 {
+        return Err(eyre!("--bucket-width must be a finite number greater than zero"));
+    }
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&args.img_path)
+        .wrap_err_with(|| format!("Failed to read directory: {}", args.img_path.display()))?
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                error!("Failed to read directory entry: {}", e);
+                None
+            }
+        })
+        .collect();
+    paths.sort();
+
+    let detections: Vec<Vec<FaceInfo>> = paths.par_iter()
+        .filter_map(|path| {
+            let Ok(img) = image::open(path) else {
+                warn!("Skipping unsupported or broken file: {}", path.display());
+                return None;
+            };
+            match detect_faces_with(&img, MODEL_DATA, &args.detector) {
+                Ok(faces) => Some(faces),
+                Err(e) => {
+                    error!("Failed detecting faces in {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    let histogram = build_histogram(&detections, args.bucket_width);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&histogram)?);
+    } else {
+        print_histogram(&histogram);
+    }
+
+    Ok(())
+}
+
+/// Counts scores into `width`-sized buckets, keeping empty buckets between the lowest and highest
+/// score so gaps in the distribution stay visible. When that would take more than `MAX_BUCKETS`,
+/// only the non-empty buckets are kept.
+fn build_histogram(detections: &[Vec<FaceInfo>], width: f64) -> Histogram {
+    const MAX_BUCKETS: i64 = 10_000;
+
+    let mut counts: BTreeMap<i64, usize> = BTreeMap::new();
+    for face in detections.iter().flatten() {
+        *counts.entry((face.score() / width).floor() as i64).or_default() += 1;
+    }
+
+    let bucket = |index: i64, count: usize| Bucket {
+        min: index as f64 * width,
+        max: (index + 1) as f64 * width,
+        count,
+    };
+    let buckets = match (counts.keys().next(), counts.keys().next_back()) {
+        (Some(&first), Some(&last)) if last.saturating_sub(first) < MAX_BUCKETS => (first..=last)
+            .map(|index| bucket(index, counts.get(&index).copied().unwrap_or(0)))
+            .collect(),
+        (Some(_), Some(_)) => {
+            warn!("The scores span more than {} buckets of that width, showing only non-empty buckets", MAX_BUCKETS);
+            counts.iter().map(|(&index, &count)| bucket(index, count)).collect()
+        }
+        _ => Vec::new(),
+    };
+
+    Histogram {
+        images: detections.len(),
+        images_without_faces: detections.iter().filter(|faces| faces.is_empty()).count(),
+        faces: detections.iter().map(Vec::len).sum(),
+        buckets,
+    }
+}
+
+fn print_histogram(histogram: &Histogram) {
+    const BAR_WIDTH: usize = 40;
+    let largest = histogram.buckets.iter().map(|b| b.count).max().unwrap_or(0).max(1);
+    for bucket in &histogram.buckets {
+        let bar = "#".repeat((bucket.count * BAR_WIDTH).div_ceil(largest));
+        println!("{:>8.2} - {:<8.2} {:<width$} {}", bucket.min, bucket.max, bar, bucket.count, width = BAR_WIDTH);
+    }
+
+    println!(
+        "{} faces in {} images, {} images without faces",
+        histogram.faces, histogram.images, histogram.images_without_faces
+    );
+}
@@ -0,0 +1,46 @@
+//! Optional animated WebP encoding, enabled with the `webp` feature. The image crate can't write
+//! WebP, so this goes through libwebp, built from source by `webp-animation`.
+
+use eyre::{eyre, Result};
+use image::RgbaImage;
+
+/// Returns an error up front when WebP output was requested from a build that can't write it.
+#[cfg(feature = "webp")]
+pub fn check_available() -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "webp"))]
+pub fn check_available() -> Result<()> {
+    Err(eyre!("WebP output requires imgrszr to be built with `--features webp`"))
+}
+
+/// Encodes `frames`, each paired with its delay in milliseconds, as an animated WebP that plays
+/// `plays` times (0 is forever).
+#[cfg(feature = "webp")]
+pub fn encode_animation(frames: &[(RgbaImage, u32)], plays: u32, size: (u32, u32)) -> Result<Vec<u8>> {
+    use webp_animation::{AnimParams, Encoder, EncoderOptions};
+
+    let options = EncoderOptions {
+        anim_params: AnimParams { loop_count: plays.min(i32::MAX as u32) as i32 },
+        ..Default::default()
+    };
+    let mut encoder = Encoder::new_with_options(size, options)
+        .map_err(|e| eyre!("Failed to start the WebP encoder: {:?}", e))?;
+
+    // WebP frames are placed by start timestamp, and the final timestamp ends the last frame
+    let mut timestamp: i32 = 0;
+    for (frame, delay_ms) in frames {
+        encoder.add_frame(frame.as_raw(), timestamp)
+            .map_err(|e| eyre!("Failed to encode WebP frame: {:?}", e))?;
+        timestamp = timestamp.saturating_add((*delay_ms).max(1).min(i32::MAX as u32) as i32);
+    }
+    let data = encoder.finalize(timestamp).map_err(|e| eyre!("Failed to finish the WebP animation: {:?}", e))?;
+
+    Ok(data.to_vec())
+}
+
+#[cfg(not(feature = "webp"))]
+pub fn encode_animation(_frames: &[(RgbaImage, u32)], _plays: u32, _size: (u32, u32)) -> Result<Vec<u8>> {
+    check_available().map(|_| Vec::new())
+}